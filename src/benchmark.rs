@@ -110,12 +110,13 @@ pub fn setup_bench(pos: &Position, args: &str) -> Vec<String>
         };
         let reader = std::io::BufReader::new(file);
         for fen in reader.lines() {
-            if fen.is_ok() {
-                break;
-            }
-            let fen = fen.unwrap();
+            let fen = match fen {
+                Ok(fen) => fen,
+                Err(_) => break,
+            };
+            let fen = fen.trim();
             if !fen.is_empty() {
-                fens.push(fen);
+                fens.push(String::from(fen));
             }
         }
     }
@@ -0,0 +1,284 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use cluster;
+use json;
+use misc;
+use tmreplay;
+use treedump;
+use uci;
+use ucioption;
+
+use std::fs::File;
+use std::io::BufRead;
+
+// Exit codes returned by the program
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+
+const USAGE: &str = "\
+Usage: rustfish [options] [command]
+
+Commands:
+  uci                          Run the UCI protocol on stdin (default)
+  bench [hash threads limit file type]
+                               Run the benchmark (default: 16 1 13 default
+                               depth)
+  votebench [hash threads limit file type]
+                               Run the benchmark and compare the moves
+                               chosen from the threads by score and by vote
+  perft <fen|startpos> <depth> Count the leaf nodes up to the given depth
+  analyse --fen <fen> [--moves <m1> ...] [--depth <d>] [--movetime <ms>]
+          [--nodes <n>]        Search a position and print the result
+  eval --fen <fen>             Print the static evaluation of a position
+  tmreplay <file>              Replay a time log with the time management
+                               options given with --option
+  treeprint <file>             Print a search tree dump as an indented tree
+                               (as JSON with --json)
+  worker <address>             Search as a worker of the cluster master
+                               listening on the address
+
+Options:
+  --option <Name>=<Value>      Set a UCI option before running the command
+  --commands <file>            Run the UCI commands in the given file
+  --json                       Report output as JSON lines
+  --help                       Print this message";
+
+// Command is the parsed command line: the UCI commands to run first, and
+// whether to read further commands from stdin afterwards, or the time log
+// to replay, the tree dump to print or the cluster master to serve instead.
+
+pub struct Command {
+    pub commands: Vec<String>,
+    pub interactive: bool,
+    pub replay: Option<String>,
+    pub tree: Option<String>,
+    pub master: Option<String>,
+}
+
+// parse() converts the command line arguments (without the program name)
+// into a list of UCI commands. Options given with --option are applied
+// immediately. On failure the exit code to return is given as error.
+
+pub fn parse(args: &[String]) -> Result<Command, i32> {
+    let mut commands = Vec::new();
+    let mut script = None;
+    let mut rest: Vec<String> = Vec::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--json" => {
+                ucioption::set("Output Format", "json");
+            }
+            "--option" => {
+                let opt = required(iter.next(), "--option")?;
+                let (name, value) = match opt.find('=') {
+                    Some(idx) => (&opt[..idx], &opt[idx+1..]),
+                    None => (opt.as_str(), ""),
+                };
+                if let Err(msg) = ucioption::check(name, value) {
+                    return usage_error(&msg);
+                }
+                ucioption::set(name, value);
+            }
+            "--commands" =>
+                script = Some(required(iter.next(), "--commands")?),
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return Err(EXIT_OK);
+            }
+            _ => rest.push(arg.clone()),
+        }
+    }
+
+    if let Some(ref file) = script {
+        commands.extend(read_script(file)?);
+    }
+
+    let subcommand = if rest.is_empty() { "uci" } else { rest[0].as_str() };
+    let sub_args = if rest.is_empty() { &rest[..] } else { &rest[1..] };
+
+    // A script without a subcommand runs to completion and exits
+    let mut interactive = false;
+    let mut replay = None;
+    let mut tree = None;
+    let mut master = None;
+
+    match subcommand {
+        "uci" => {
+            expect_no_args(sub_args)?;
+            interactive = script.is_none();
+        }
+        "bench" | "votebench" => commands.push(format!("{} {}", subcommand,
+            sub_args.join(" "))),
+        "perft" => {
+            if sub_args.len() < 2 {
+                return usage_error("perft needs a position and a depth");
+            }
+            let depth: u32 = number(&sub_args[sub_args.len()-1], "depth")?;
+            let fen = sub_args[..sub_args.len()-1].join(" ");
+            commands.push(position_cmd(&fen, &[]));
+            commands.push(format!("go perft {}", depth));
+        }
+        "analyse" | "analyze" => {
+            let mut fen = String::from("startpos");
+            let mut moves = Vec::new();
+            let mut go = String::from("go");
+            let mut iter = sub_args.iter().peekable();
+            while let Some(arg) = iter.next() {
+                match arg.as_str() {
+                    "--fen" => fen = required(iter.next(), "--fen")?,
+                    "--moves" => {
+                        while let Some(m) = iter.peek() {
+                            if m.starts_with("--") {
+                                break;
+                            }
+                            moves.push(m.to_string());
+                            iter.next();
+                        }
+                    }
+                    "--depth" | "--movetime" | "--nodes" | "--mate" => {
+                        let n: u64 =
+                            number(&required(iter.next(), arg)?, arg)?;
+                        go.push_str(&format!(" {} {}", &arg[2..], n));
+                    }
+                    _ => return usage_error(&format!("unknown argument {}",
+                        arg)),
+                }
+            }
+            if go == "go" {
+                go.push_str(" depth 20");
+            }
+            commands.push(position_cmd(&fen, &moves));
+            commands.push(go);
+        }
+        "eval" => {
+            if sub_args.len() != 2 || sub_args[0] != "--fen" {
+                return usage_error("eval needs --fen <fen>");
+            }
+            commands.push(position_cmd(&sub_args[1], &[]));
+            commands.push(String::from("eval"));
+        }
+        "tmreplay" => {
+            if sub_args.len() != 1 {
+                return usage_error("tmreplay needs a time log file");
+            }
+            replay = Some(sub_args[0].clone());
+        }
+        "treeprint" => {
+            if sub_args.len() != 1 {
+                return usage_error("treeprint needs a tree dump file");
+            }
+            tree = Some(sub_args[0].clone());
+        }
+        "worker" => {
+            if sub_args.len() != 1 {
+                return usage_error("worker needs the address of the master");
+            }
+            master = Some(sub_args[0].clone());
+        }
+        _ => return usage_error(&format!("unknown command {}", subcommand)),
+    }
+
+    Ok(Command {
+        commands,
+        interactive,
+        replay,
+        tree,
+        master,
+    })
+}
+
+fn position_cmd(fen: &str, moves: &[String]) -> String {
+    let mut cmd = if fen == "startpos" { String::from("position startpos") }
+        else { format!("position fen {}", fen) };
+    if !moves.is_empty() {
+        cmd.push_str(" moves ");
+        cmd.push_str(&moves.join(" "));
+    }
+    cmd
+}
+
+fn read_script(file: &str) -> Result<Vec<String>, i32> {
+    let file = match File::open(file) {
+        Ok(f) => f,
+        Err(_) => {
+            eprintln!("Unable to open file {}", file);
+            return Err(EXIT_FAILURE);
+        }
+    };
+
+    let mut commands = Vec::new();
+    for line in std::io::BufReader::new(file).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return Err(EXIT_FAILURE),
+        };
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            commands.push(String::from(line));
+        }
+    }
+    Ok(commands)
+}
+
+fn required(arg: Option<&String>, flag: &str) -> Result<String, i32> {
+    match arg {
+        Some(a) => Ok(a.clone()),
+        None => usage_error(&format!("{} needs an argument", flag)),
+    }
+}
+
+fn number<T: std::str::FromStr>(arg: &str, what: &str) -> Result<T, i32> {
+    match arg.parse() {
+        Ok(n) => Ok(n),
+        Err(_) => usage_error(&format!("invalid {}: {}", what, arg)),
+    }
+}
+
+fn expect_no_args(args: &[String]) -> Result<(), i32> {
+    if args.is_empty() { Ok(()) }
+    else { usage_error(&format!("unexpected argument {}", args[0])) }
+}
+
+fn usage_error<T>(msg: &str) -> Result<T, i32> {
+    eprintln!("rustfish: {}\n\n{}", msg, USAGE);
+    Err(EXIT_USAGE)
+}
+
+// run() parses the command line and runs the engine accordingly. It
+// returns the exit code of the program.
+
+pub fn run(args: &[String]) -> i32 {
+    let cmd = match parse(args) {
+        Ok(cmd) => cmd,
+        Err(code) => return code,
+    };
+
+    // The banner comes after the options, which may ask for JSON output
+    if uci::json() {
+        println!("{}", json::Object::new("engine")
+            .str("name", &misc::engine_info(false)).end());
+    } else {
+        println!("{}", misc::engine_info(false));
+    }
+
+    if let Some(ref file) = cmd.replay {
+        return tmreplay::run(file);
+    }
+
+    if let Some(ref file) = cmd.tree {
+        return treedump::print(file);
+    }
+
+    if let Some(ref address) = cmd.master {
+        if !uci::cmd_loop(&cmd.commands, false) {
+            return EXIT_FAILURE;
+        }
+        return cluster::run_worker(address);
+    }
+
+    if uci::cmd_loop(&cmd.commands, cmd.interactive) { EXIT_OK }
+    else { EXIT_FAILURE }
+}
@@ -0,0 +1,598 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use cli;
+use search;
+use threads;
+use threads::{PosData, SearchResult};
+use tt;
+use types::*;
+use uci;
+use ucioption;
+
+use std;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// In cluster mode several Rustfish processes on one host search together.
+// The process the GUI talks to is the master: setting its "Cluster" option
+// to an address makes it listen there, and worker processes started with
+// "rustfish worker <address>" connect to it. An address is either
+// "unix:<path>" for a Unix socket or "[host:]port" for TCP, where the host
+// defaults to 127.0.0.1.
+//
+// The processes exchange text lines. For every search the master sends the
+// position and a "go infinite" to the workers, and "stop" when its own
+// search ends. While searching, all processes send the TT entries they
+// store with at least "Cluster TT Depth" plies, which the master also
+// relays to the other workers, and the workers report their node counts,
+// which the master adds to its own. At the end each worker sends its best
+// line, which the master counts like those of its own threads when it
+// chooses the best move. The master reads "Cluster TT Depth" at the start
+// of every search and passes it on to the workers.
+//
+// Master to worker:
+//   cluster_search <id> <depth>          a new search follows, sharing TT
+//                                        entries of at least depth plies
+//   <UCI command>                        setoption, position, go, stop, ...
+// Both ways:
+//   tt <key> <move> <value> <eval> <bound> <depth>
+// Worker to master:
+//   nodes <id> <nodes>
+//   result <id> <depth> <score> <move> ...
+
+// How long the master waits for the results of the workers
+const RESULT_TIMEOUT: u64 = 1000;
+
+// Interval of sending TT entries and node counts, in milliseconds
+const SHARE_INTERVAL: u64 = 100;
+
+// Upper bound of TT entries queued for sending
+const MAX_QUEUED: usize = 1 << 16;
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn try_clone(&self) -> std::io::Result<Stream> {
+        match *self {
+            Stream::Tcp(ref s) => s.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(ref s) => s.try_clone().map(Stream::Unix),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn accept(&self) -> std::io::Result<Stream> {
+        match *self {
+            Listener::Tcp(ref l) => l.accept().map(|(s, _)| {
+                let _ = s.set_nodelay(true);
+                Stream::Tcp(s)
+            }),
+            #[cfg(unix)]
+            Listener::Unix(ref l) => l.accept().map(|(s, _)| Stream::Unix(s)),
+        }
+    }
+}
+
+fn tcp_address(address: &str) -> String {
+    if address.contains(':') { String::from(address) }
+    else { format!("127.0.0.1:{}", address) }
+}
+
+fn bind(address: &str) -> std::io::Result<Listener> {
+    if let Some(path) = address.strip_prefix("unix:") {
+        #[cfg(unix)]
+        {
+            // A socket file left behind by an earlier master is replaced
+            let _ = std::fs::remove_file(path);
+            return UnixListener::bind(path).map(Listener::Unix);
+        }
+        #[cfg(not(unix))]
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported,
+            path.to_string()));
+    }
+    TcpListener::bind(tcp_address(address)).map(Listener::Tcp)
+}
+
+fn connect(address: &str) -> std::io::Result<Stream> {
+    if let Some(path) = address.strip_prefix("unix:") {
+        #[cfg(unix)]
+        return UnixStream::connect(path).map(Stream::Unix);
+        #[cfg(not(unix))]
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported,
+            path.to_string()));
+    }
+    TcpStream::connect(tcp_address(address)).map(|s| {
+        let _ = s.set_nodelay(true);
+        Stream::Tcp(s)
+    })
+}
+
+// A TT entry as exchanged between the processes, with the value already
+// adjusted for storing in the TT
+#[derive(Clone, Copy)]
+struct Entry {
+    key: u64,
+    m: u32,
+    value: i32,
+    eval: i32,
+    bound: u32,
+    depth: i32,
+}
+
+impl Entry {
+    fn line(&self) -> String {
+        format!("tt {} {} {} {} {} {}\n", self.key, self.m, self.value,
+            self.eval, self.bound, self.depth)
+    }
+
+    fn parse(args: &[&str]) -> Option<Entry> {
+        if args.len() != 6 {
+            return None;
+        }
+        Some(Entry {
+            key: args[0].parse().ok()?,
+            m: args[1].parse().ok()?,
+            value: args[2].parse().ok()?,
+            eval: args[3].parse().ok()?,
+            bound: args[4].parse().ok()?,
+            depth: args[5].parse().ok()?,
+        })
+    }
+
+    fn store(&self) {
+        let (tte, _) = tt::probe(Key(self.key));
+        tte.save(Key(self.key), Value(self.value), Bound(self.bound),
+            self.depth * ONE_PLY, Move(self.m), Value(self.eval),
+            tt::generation());
+    }
+}
+
+// writer() starts a thread writing the text sent to the returned channel to
+// a stream, so that nobody has to wait for a slow worker while holding a
+// lock. The thread ends when the channel is dropped or the stream fails.
+
+fn writer(mut out: Stream) -> Sender<String> {
+    let (tx, rx) = channel::<String>();
+    thread::spawn(move || {
+        for text in rx {
+            if out.write_all(text.as_bytes()).is_err() {
+                break;
+            }
+        }
+    });
+    tx
+}
+
+// A worker as seen by the master
+struct Remote {
+    id: usize,
+    out: Sender<String>,
+    nodes: u64,
+    result: Option<SearchResult>,
+}
+
+struct Master {
+    remotes: Vec<Remote>,
+    next_id: usize,
+    search_id: u64,
+    searching: bool,
+}
+
+static MASTER: Mutex<Master> = Mutex::new(Master {
+    remotes: Vec::new(),
+    next_id: 0,
+    search_id: 0,
+    searching: false,
+});
+
+// Signalled when a worker sends its result or disconnects
+static RESULTS: Condvar = Condvar::new();
+
+// Nodes searched by the workers in the current search, kept outside of
+// MASTER so that the search threads can read it without locking
+static REMOTE_NODES: AtomicU64 = AtomicU64::new(0);
+
+static LISTENING: AtomicBool = AtomicBool::new(false);
+
+// Connection of a worker to its master
+static UPLINK: Mutex<Option<Stream>> = Mutex::new(None);
+static WORKER_SEARCH: AtomicU64 = AtomicU64::new(0);
+static WORKER_SEARCHING: AtomicBool = AtomicBool::new(false);
+
+// TT entries stored by this process and waiting to be sent
+static QUEUE: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+// Minimum depth in plies of shared TT entries, 0 when not in a cluster
+static SHARE_DEPTH: AtomicI32 = AtomicI32::new(0);
+
+// share() queues a TT entry just stored by the search for sending to the
+// other processes if it is deep enough.
+
+#[inline]
+pub fn share(
+    key: Key, v: Value, b: Bound, d: Depth, m: Move, ev: Value
+) {
+    let min = SHARE_DEPTH.load(Ordering::Relaxed);
+    if min == 0 || d < min * ONE_PLY {
+        return;
+    }
+    let mut queue = QUEUE.lock().unwrap();
+    if queue.len() < MAX_QUEUED {
+        queue.push(Entry {
+            key: key.0,
+            m: m.0,
+            value: v.0,
+            eval: ev.0,
+            bound: b.0,
+            depth: d / ONE_PLY,
+        });
+    }
+}
+
+fn take_queue() -> Vec<Entry> {
+    std::mem::take(&mut *QUEUE.lock().unwrap())
+}
+
+// listen() is called when the "Cluster" option is set. It makes this
+// process the master of a cluster and accepts workers in the background.
+
+pub fn listen(address: &str) {
+    if address.is_empty() || address == "<empty>" {
+        return;
+    }
+    if LISTENING.load(Ordering::Relaxed) {
+        println!("info string Cluster already listening");
+        return;
+    }
+    let listener = match bind(address) {
+        Ok(l) => l,
+        Err(e) => {
+            println!("info string Unable to listen on {}: {}", address, e);
+            return;
+        }
+    };
+    LISTENING.store(true, Ordering::Relaxed);
+    println!("info string Cluster listening on {}", address);
+
+    thread::spawn(move || {
+        while let Ok(stream) = listener.accept() {
+            add_worker(stream);
+        }
+    });
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_millis(SHARE_INTERVAL));
+        let entries = take_queue();
+        if !entries.is_empty() {
+            let lines: String = entries.iter().map(|e| e.line()).collect();
+            broadcast(&lines, None);
+        }
+    });
+}
+
+fn add_worker(stream: Stream) {
+    let out = match stream.try_clone() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    let id = {
+        let mut master = MASTER.lock().unwrap();
+        let id = master.next_id;
+        master.next_id += 1;
+        master.remotes.push(Remote {
+            id,
+            out: writer(out),
+            nodes: 0,
+            result: None,
+        });
+        id
+    };
+    println!("info string Cluster worker {} connected", id);
+
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            match line {
+                Ok(line) => from_worker(id, &line),
+                Err(_) => break,
+            }
+        }
+        let mut master = MASTER.lock().unwrap();
+        master.remotes.retain(|r| r.id != id);
+        update_nodes(&master);
+        if master.remotes.is_empty() {
+            SHARE_DEPTH.store(0, Ordering::Relaxed);
+        }
+        RESULTS.notify_all();
+        println!("info string Cluster worker {} disconnected", id);
+    });
+}
+
+// broadcast() queues text for all workers except the given one. Workers
+// that cannot be written to are dropped when their reader notices.
+
+fn broadcast(text: &str, except: Option<usize>) {
+    let master = MASTER.lock().unwrap();
+    for r in master.remotes.iter() {
+        if Some(r.id) != except {
+            let _ = r.out.send(String::from(text));
+        }
+    }
+}
+
+// update_nodes() publishes the node total of the workers
+
+fn update_nodes(master: &Master) {
+    let nodes = if master.searching {
+        master.remotes.iter().map(|r| r.nodes).sum()
+    } else {
+        0
+    };
+    REMOTE_NODES.store(nodes, Ordering::Relaxed);
+}
+
+fn from_worker(id: usize, line: &str) {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    match args.first() {
+        Some(&"tt") => if let Some(e) = Entry::parse(&args[1..]) {
+            e.store();
+            broadcast(&format!("{}\n", line), Some(id));
+        },
+        Some(&"nodes") if args.len() == 3 => {
+            let mut master = MASTER.lock().unwrap();
+            let current = master.search_id.to_string();
+            if master.searching && args[1] == current {
+                if let Some(r) = master.remotes.iter_mut()
+                    .find(|r| r.id == id)
+                {
+                    r.nodes = args[2].parse().unwrap_or(r.nodes);
+                }
+                update_nodes(&master);
+            }
+        }
+        Some(&"result") if args.len() >= 5 => {
+            let mut master = MASTER.lock().unwrap();
+            if args[1] != master.search_id.to_string() {
+                return;
+            }
+            let nums: Vec<i64> = args[2..].iter()
+                .filter_map(|a| a.parse().ok()).collect();
+            if nums.len() != args.len() - 2 {
+                return;
+            }
+            if let Some(r) = master.remotes.iter_mut().find(|r| r.id == id) {
+                r.result = Some(SearchResult {
+                    depth: nums[0] as i32 * ONE_PLY,
+                    score: Value(nums[1] as i32),
+                    pv: nums[2..].iter().map(|&m| Move(m as u32)).collect(),
+                });
+            }
+            RESULTS.notify_all();
+        }
+        _ => {}
+    }
+}
+
+// start_search() is called by the master when a search starts. It lets the
+// workers search the same position and root moves until told to stop.
+
+pub fn start_search(
+    pos_data: &Arc<RwLock<PosData>>, limits: &search::LimitsType,
+    searchmoves: &[Move], excludemoves: &[Move], chess960: bool
+) {
+    let mut master = MASTER.lock().unwrap();
+    if master.remotes.is_empty() || limits.perft != 0 || limits.mate != 0 {
+        return;
+    }
+    master.search_id += 1;
+    master.searching = true;
+    // The option may have changed since the last search
+    let share_depth = ucioption::get_i32("Cluster TT Depth");
+    SHARE_DEPTH.store(share_depth, Ordering::Relaxed);
+    for r in master.remotes.iter_mut() {
+        r.nodes = 0;
+        r.result = None;
+    }
+    update_nodes(&master);
+
+    let move_list = |moves: &[Move]| moves.iter()
+        .map(|&m| uci::move_str(m, chess960)).collect::<Vec<_>>().join(" ");
+
+    let pos_data = pos_data.read().unwrap();
+    let mut text = format!("cluster_search {} {}\n", master.search_id,
+        share_depth);
+    text.push_str(&format!("setoption name UCI_Chess960 value {}\n",
+        chess960));
+    text.push_str(&format!("position fen {}", pos_data.fen));
+    if !pos_data.moves.is_empty() {
+        text.push_str(&format!(" moves {}", move_list(&pos_data.moves)));
+    }
+    text.push_str("\ngo infinite");
+    if !searchmoves.is_empty() {
+        text.push_str(&format!(" searchmoves {}", move_list(searchmoves)));
+    }
+    if !excludemoves.is_empty() {
+        text.push_str(&format!(" excludemoves {}", move_list(excludemoves)));
+    }
+    text.push('\n');
+
+    for r in master.remotes.iter() {
+        let _ = r.out.send(text.clone());
+    }
+}
+
+// finish_search() is called by the master when its search has stopped. It
+// stops the workers and returns the results that arrive in time.
+
+pub fn finish_search() -> Vec<SearchResult> {
+    let mut master = MASTER.lock().unwrap();
+    if !master.searching {
+        return Vec::new();
+    }
+    for r in master.remotes.iter() {
+        let _ = r.out.send(String::from("stop\n"));
+    }
+
+    let deadline = Instant::now() + Duration::from_millis(RESULT_TIMEOUT);
+    while master.remotes.iter().any(|r| r.result.is_none()) {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        master = RESULTS.wait_timeout(master, deadline - now).unwrap().0;
+    }
+
+    master.searching = false;
+    update_nodes(&master);
+    master.remotes.iter_mut().filter_map(|r| r.result.take()).collect()
+}
+
+// nodes() returns the nodes searched by the workers in the current search
+
+pub fn nodes() -> u64 {
+    REMOTE_NODES.load(Ordering::Relaxed)
+}
+
+// new_game() passes "ucinewgame" on to the workers
+
+pub fn new_game() {
+    broadcast("ucinewgame\n", None);
+}
+
+fn send_up(text: &str) {
+    if let Some(ref mut out) = *UPLINK.lock().unwrap() {
+        let _ = out.write_all(text.as_bytes());
+    }
+}
+
+// search_done() is called by a worker at the end of a search to send its
+// result to the master.
+
+pub fn search_done(depth: Depth, score: Value, pv: &[Move]) {
+    if !WORKER_SEARCHING.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let mut text = format!("result {} {} {}",
+        WORKER_SEARCH.load(Ordering::Relaxed), depth / ONE_PLY, score.0);
+    for m in pv {
+        text.push_str(&format!(" {}", m.0));
+    }
+    text.push('\n');
+    send_up(&text);
+}
+
+// run_worker() is run by the "worker" command. It connects to the master,
+// waiting for it to come up for a while, and then runs the commands the
+// master sends. It returns the exit code of the program.
+
+pub fn run_worker(address: &str) -> i32 {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let stream = loop {
+        match connect(address) {
+            Ok(s) => break s,
+            Err(e) => {
+                if Instant::now() >= deadline {
+                    eprintln!("Unable to connect to {}: {}", address, e);
+                    return cli::EXIT_FAILURE;
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+    };
+    let out = match stream.try_clone() {
+        Ok(s) => s,
+        Err(_) => return cli::EXIT_FAILURE,
+    };
+    *UPLINK.lock().unwrap() = Some(out);
+    println!("info string Cluster worker connected to {}", address);
+
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_millis(SHARE_INTERVAL));
+        let mut text: String = take_queue().iter().map(|e| e.line())
+            .collect();
+        if WORKER_SEARCHING.load(Ordering::Relaxed) {
+            text.push_str(&format!("nodes {} {}\n",
+                WORKER_SEARCH.load(Ordering::Relaxed),
+                threads::nodes_searched()));
+        }
+        if !text.is_empty() {
+            send_up(&text);
+        }
+    });
+
+    let mut lines = BufReader::new(stream).lines();
+    uci::serve(|| {
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => return None, // The master has gone
+        };
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.first() {
+            Some(&"tt") => {
+                if let Some(e) = Entry::parse(&args[1..]) {
+                    e.store();
+                }
+                Some(String::new())
+            }
+            Some(&"cluster_search") => {
+                // The result of the last search must be sent first
+                threads::wait_for_main();
+                WORKER_SEARCH.store(args.get(1)
+                    .and_then(|id| id.parse().ok()).unwrap_or(0),
+                    Ordering::Relaxed);
+                WORKER_SEARCHING.store(true, Ordering::Relaxed);
+                SHARE_DEPTH.store(args.get(2).and_then(|d| d.parse().ok())
+                    .unwrap_or_else(|| ucioption::get_i32("Cluster TT Depth")),
+                    Ordering::Relaxed);
+                Some(String::new())
+            }
+            _ => Some(line),
+        }
+    });
+
+    cli::EXIT_OK
+}
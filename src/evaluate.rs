@@ -872,7 +872,15 @@ fn evaluate_scale_factor(
 // of the evaluation and returns the value of the position from the point of
 // view of the side to move.
 
+#[inline]
 pub fn evaluate(pos: &Position) -> Value {
+    evaluate_with_contempt(pos, contempt())
+}
+
+// evaluate_with_contempt() is evaluate() with the given contempt, from
+// White's point of view, instead of that of the current search.
+
+pub fn evaluate_with_contempt(pos: &Position, contempt: Score) -> Value {
     debug_assert!(pos.checkers() == 0);
 
     // Probe the material hash table
@@ -888,7 +896,7 @@ pub fn evaluate(pos: &Position) -> Value {
     // in the position object (material + piece square tables) and the
     // material imbalance. Score is computer internally from the white point
     // of view.
-    let mut score = pos.psq_score() + me.imbalance() + contempt();
+    let mut score = pos.psq_score() + me.imbalance() + contempt;
 
     // Probe the pawn hash table
     let pe = pawns::probe(pos);
@@ -0,0 +1,272 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std;
+use std::fmt::Display;
+
+// Object is a minimal writer for the single-line JSON objects that the
+// engine emits when the "Output Format" option is set to JSON. Keys are
+// written in the order in which they are added.
+
+pub struct Object {
+    buf: String,
+}
+
+impl Object {
+    pub fn new(kind: &str) -> Object {
+        let obj = Object { buf: String::from("{") };
+        obj.str("type", kind)
+    }
+
+    fn key(mut self, key: &str) -> Object {
+        if self.buf.len() > 1 {
+            self.buf.push(',');
+        }
+        self.buf.push_str(&quote(key));
+        self.buf.push(':');
+        self
+    }
+
+    pub fn str(self, key: &str, val: &str) -> Object {
+        let mut obj = self.key(key);
+        obj.buf.push_str(&quote(val));
+        obj
+    }
+
+    pub fn num<T: Display>(self, key: &str, val: T) -> Object {
+        let mut obj = self.key(key);
+        obj.buf.push_str(&val.to_string());
+        obj
+    }
+
+    pub fn strs(self, key: &str, vals: &[String]) -> Object {
+        let mut obj = self.key(key);
+        obj.buf.push('[');
+        for (i, v) in vals.iter().enumerate() {
+            if i > 0 {
+                obj.buf.push(',');
+            }
+            obj.buf.push_str(&quote(v));
+        }
+        obj.buf.push(']');
+        obj
+    }
+
+    pub fn nums<T: Display>(self, key: &str, vals: &[T]) -> Object {
+        let mut obj = self.key(key);
+        obj.buf.push('[');
+        for (i, v) in vals.iter().enumerate() {
+            if i > 0 {
+                obj.buf.push(',');
+            }
+            obj.buf.push_str(&v.to_string());
+        }
+        obj.buf.push(']');
+        obj
+    }
+
+    // raw() adds a value that is already valid JSON, e.g. a nested object
+    pub fn raw(self, key: &str, json: &str) -> Object {
+        let mut obj = self.key(key);
+        obj.buf.push_str(json);
+        obj
+    }
+
+    pub fn end(mut self) -> String {
+        self.buf.push('}');
+        self.buf
+    }
+}
+
+// quote() converts a string to a JSON string literal
+
+pub fn quote(s: &str) -> String {
+    let mut q = String::with_capacity(s.len() + 2);
+    q.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => q.push_str("\\\""),
+            '\\' => q.push_str("\\\\"),
+            '\n' => q.push_str("\\n"),
+            '\r' => q.push_str("\\r"),
+            '\t' => q.push_str("\\t"),
+            c if (c as u32) < 0x20 =>
+                q.push_str(&format!("\\u{:04x}", c as u32)),
+            c => q.push(c),
+        }
+    }
+    q.push('"');
+    q
+}
+
+// Value is a parsed JSON value, as read back by the tools that process the
+// engine's own JSON output.
+
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    // get() returns the member 'key' of an object
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match *self {
+            Value::Object(ref members) =>
+                members.iter().find(|m| m.0 == key).map(|m| &m.1),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Num(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::Str(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match *self {
+            Value::Array(ref a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+// parse() parses a JSON text. It returns None if the text is not valid JSON.
+
+pub fn parse(s: &str) -> Option<Value> {
+    let mut parser = Parser { chars: s.chars().peekable() };
+    let value = parser.value()?;
+    parser.skip_ws();
+    if parser.chars.peek().is_some() { None } else { Some(value) }
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_ws(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, word: &str) -> Option<()> {
+        for c in word.chars() {
+            if self.chars.next()? != c {
+                return None;
+            }
+        }
+        Some(())
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        self.skip_ws();
+        match *self.chars.peek()? {
+            'n' => self.expect("null").map(|_| Value::Null),
+            't' => self.expect("true").map(|_| Value::Bool(true)),
+            'f' => self.expect("false").map(|_| Value::Bool(false)),
+            '"' => self.string().map(Value::Str),
+            '[' => {
+                self.chars.next();
+                let mut vals = Vec::new();
+                self.skip_ws();
+                if self.chars.peek() == Some(&']') {
+                    self.chars.next();
+                    return Some(Value::Array(vals));
+                }
+                loop {
+                    vals.push(self.value()?);
+                    self.skip_ws();
+                    match self.chars.next()? {
+                        ',' => {}
+                        ']' => return Some(Value::Array(vals)),
+                        _ => return None,
+                    }
+                }
+            }
+            '{' => {
+                self.chars.next();
+                let mut members = Vec::new();
+                self.skip_ws();
+                if self.chars.peek() == Some(&'}') {
+                    self.chars.next();
+                    return Some(Value::Object(members));
+                }
+                loop {
+                    self.skip_ws();
+                    let key = self.string()?;
+                    self.skip_ws();
+                    self.expect(":")?;
+                    members.push((key, self.value()?));
+                    self.skip_ws();
+                    match self.chars.next()? {
+                        ',' => {}
+                        '}' => return Some(Value::Object(members)),
+                        _ => return None,
+                    }
+                }
+            }
+            _ => self.number(),
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.expect("\"")?;
+        let mut s = String::new();
+        loop {
+            match self.chars.next()? {
+                '"' => return Some(s),
+                '\\' => match self.chars.next()? {
+                    'n' => s.push('\n'),
+                    'r' => s.push('\r'),
+                    't' => s.push('\t'),
+                    'b' => s.push('\u{8}'),
+                    'f' => s.push('\u{c}'),
+                    'u' => {
+                        let hex: String = (0..4)
+                            .filter_map(|_| self.chars.next()).collect();
+                        let code = u32::from_str_radix(&hex, 16).ok()?;
+                        s.push(std::char::from_u32(code)?);
+                    }
+                    c => s.push(c),
+                },
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Option<Value> {
+        let mut s = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !(c.is_ascii_digit() || "+-.eE".contains(c)) {
+                break;
+            }
+            s.push(c);
+            self.chars.next();
+        }
+        s.parse().ok().map(Value::Num)
+    }
+}
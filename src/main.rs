@@ -1,34 +1,45 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+#[cfg(target_os = "linux")]
+extern crate libc;
 extern crate memmap;
 
 mod benchmark;
 mod bitbases;
 #[macro_use]
 mod bitboard;
+mod cli;
+mod cluster;
 mod endgame;
 mod evaluate;
+mod json;
+mod mate;
 mod material;
+mod mcts;
 mod misc;
 mod movegen;
 mod movepick;
+mod numa;
 mod pawns;
+mod pns;
 mod position;
 mod psqt;
 mod search;
 mod tb;
 mod threads;
 mod timeman;
+mod tmreplay;
+mod treedump;
 mod tt;
 mod types;
 mod uci;
 mod ucioption;
 
+use std::env;
+use std::process;
 use std::thread;
 
 fn main() {
-    println!("{}", misc::engine_info(false));
-
     ucioption::init();
     psqt::init();
     bitboard::init();
@@ -37,20 +48,24 @@ fn main() {
     search::init();
     pawns::init();
     endgame::init();
-    tt::resize(ucioption::get_i32("Hash") as usize);
+    tt::resize(ucioption::get_i32("Hash") as usize,
+        ucioption::get_bool("Large Pages"));
     threads::init(ucioption::get_i32("Threads") as usize);
     tb::init(ucioption::get_string("SyzygyPath"));
     search::clear();
 
+    let args: Vec<String> = env::args().skip(1).collect();
+
     // To avoid a stack overflow, we create a thread with a large
     // enough stack size to run the UI.
     let builder = thread::Builder::new().stack_size(16 * 1024 * 1024);
-    let ui_thread = builder.spawn(|| uci::cmd_loop()).unwrap();
-    let _ = ui_thread.join();
-    // uci::cmd_loop();
+    let ui_thread = builder.spawn(move || cli::run(&args)).unwrap();
+    let code = ui_thread.join().unwrap_or(cli::EXIT_FAILURE);
 
     threads::free();
     tb::free();
     tt::free();
     ucioption::free();
+
+    process::exit(code);
 }
@@ -0,0 +1,242 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use json;
+use movegen::*;
+use position::Position;
+use search;
+use threads;
+use timeman;
+use types::*;
+use uci;
+use ucioption;
+
+use std;
+use std::io::stdout;
+use std::io::Write;
+
+// The mate solver is run for "go mate N" instead of the normal search. It is
+// an iterative deepening alpha-beta search for a mate in 1, 2, ... up to N
+// moves. Apart from mate distance pruning it prunes nothing, so when no mate
+// is found there is none within N moves. With the "Mate Checks Only" option
+// the attacker only tries checking moves, which is much faster but finds
+// only mates in which every move of the attacker gives check.
+//
+// Lines that do not end in mate within the horizon and draws by repetition
+// or the 50-move rule are scored as draws, so a positive score is always a
+// proven mate. The defender delays the mate as long as possible, so the
+// principal variation is the full forced line.
+
+struct Solver {
+    checks_only: bool,
+    pv: Vec<Vec<Move>>,
+}
+
+impl Solver {
+    fn solve(
+        &mut self, pos: &mut Position, depth: i32, ply: i32, mut alpha: Value,
+        mut beta: Value
+    ) -> Value {
+        self.pv[ply as usize].clear();
+
+        pos.calls_cnt -= 1;
+        if pos.calls_cnt < 0 {
+            pos.calls_cnt = 4095;
+            search::update_counters(pos);
+            search::check_time(pos);
+        }
+
+        if threads::stop() || (ply > 0 && pos.is_draw(ply)) {
+            return Value::DRAW;
+        }
+
+        // Mate distance pruning. Even if we mate at the next move our score
+        // would be at best mate_in(ply + 1), but if alpha is already bigger
+        // because a shorter mate was found upward in the tree then there is
+        // no need to search further.
+        alpha = std::cmp::max(mated_in(ply), alpha);
+        beta = std::cmp::min(mate_in(ply + 1), beta);
+        if alpha >= beta {
+            return alpha;
+        }
+
+        if ply > 0 && MoveList::new::<Legal>(pos).len() == 0 {
+            return if pos.checkers() != 0 { mated_in(ply) }
+                else { Value::DRAW };
+        }
+
+        if depth == 0 {
+            return Value::DRAW;
+        }
+
+        let mut best_value = -Value::INFINITE;
+
+        for m in self.ordered_moves(pos, depth, ply) {
+            let gives_check = pos.gives_check(m);
+            pos.do_move(m, gives_check);
+            let value = -self.solve(pos, depth - 1, ply + 1, -beta, -alpha);
+            pos.undo_move(m);
+
+            if threads::stop() {
+                return Value::DRAW;
+            }
+
+            if value > best_value {
+                best_value = value;
+
+                if value > alpha {
+                    let (pv, child) = self.pv.split_at_mut(ply as usize + 1);
+                    pv[ply as usize].clear();
+                    pv[ply as usize].push(m);
+                    pv[ply as usize].extend_from_slice(&child[0]);
+
+                    if value >= beta {
+                        break;
+                    }
+                    alpha = value;
+                }
+            }
+        }
+
+        // The attacker may have had no move to try
+        if best_value == -Value::INFINITE {
+            return Value::DRAW;
+        }
+
+        best_value
+    }
+
+    // ordered_moves() returns the moves to search. The attacker (at even
+    // plies) skips quiet moves where they cannot mate, and tries first the
+    // moves that leave the defender the fewest replies. The defender tries
+    // captures first.
+
+    fn ordered_moves(
+        &self, pos: &mut Position, depth: i32, ply: i32
+    ) -> Vec<Move> {
+        let attacker = ply % 2 == 0;
+
+        let candidates: Vec<Move> = if ply == 0 {
+            pos.root_moves.iter().map(|rm| rm.pv[0]).collect()
+        } else {
+            MoveList::new::<Legal>(pos).collect()
+        };
+
+        if !attacker {
+            let (mut moves, quiets): (Vec<Move>, Vec<Move>) = candidates
+                .into_iter().partition(|&m| pos.capture_or_promotion(m));
+            moves.extend(quiets);
+            return moves;
+        }
+
+        // A mating move must give check
+        let mut moves: Vec<(Move, usize)> = Vec::new();
+        for m in candidates {
+            let gives_check = pos.gives_check(m);
+            if !gives_check && (self.checks_only || depth == 1) {
+                continue;
+            }
+            pos.do_move(m, gives_check);
+            let replies = MoveList::new::<Legal>(pos).len();
+            pos.undo_move(m);
+            moves.push((m, replies));
+        }
+
+        moves.sort_by_key(|&(_, replies)| replies);
+        moves.into_iter().map(|(m, _)| m).collect()
+    }
+}
+
+// search() runs the mate solver on the root position. If a mate is found,
+// the mating line is stored as the principal variation of the first root
+// move. Otherwise a null move is put in front of the root moves, so that
+// "bestmove 0000" is reported rather than an arbitrary move.
+
+pub fn search(pos: &mut Position) {
+    let mut solver = Solver {
+        checks_only: ucioption::get_bool("Mate Checks Only"),
+        pv: vec![Vec::new(); 2 * search::limits().mate as usize + 2],
+    };
+
+    pos.calls_cnt = 0;
+
+    // The solver completes no iteration of the normal search, which keeps
+    // the stale results of the helper threads out of the choice of the
+    // best move
+    pos.completed_depth = Depth::ZERO;
+
+    let mut found = false;
+
+    for n in 1..(search::limits().mate as i32 + 1) {
+        let depth = 2 * n - 1;
+        let value = solver.solve(pos, depth, 0, -Value::INFINITE,
+            Value::INFINITE);
+
+        if threads::stop() {
+            break;
+        }
+
+        search::update_counters(pos);
+        found = value >= Value::MATE_IN_MAX_PLY;
+
+        if found {
+            let pv = solver.pv[0].clone();
+            let idx = pos.root_moves.iter().position(|rm| rm.pv[0] == pv[0])
+                .unwrap();
+            pos.root_moves.swap(0, idx);
+            pos.root_moves[0].score = value;
+            pos.root_moves[0].pv = pv;
+        }
+
+        print_info(pos, depth, if found { Some(value) } else { None });
+
+        if found {
+            break;
+        }
+    }
+
+    if !found {
+        if !threads::stop() {
+            println!("info string No mate in {} found{}",
+                search::limits().mate, if solver.checks_only
+                    { " with checking moves only" } else { "" });
+        }
+        let mut rm = search::RootMove::new(Move::NULL);
+        rm.score = Value::DRAW;
+        pos.root_moves.insert(0, rm);
+    }
+}
+
+fn print_info(pos: &Position, depth: i32, value: Option<Value>) {
+    let elapsed = timeman::elapsed() + 1;
+    let nodes = threads::nodes_searched();
+    let nps = nodes * 1000 / (elapsed as u64);
+    let pv: Vec<String> = match value {
+        Some(_) => pos.root_moves[0].pv.iter()
+            .map(|&m| uci::move_str(m, pos.is_chess960())).collect(),
+        None => Vec::new(),
+    };
+
+    if uci::json() {
+        let mut obj = json::Object::new("info").num("depth", depth);
+        if let Some(v) = value {
+            obj = obj.raw("score", &uci::value_json(v));
+        }
+        obj = obj.num("nodes", nodes).num("nps", nps).num("time", elapsed);
+        if value.is_some() {
+            obj = obj.strs("pv", &pv);
+        }
+        println!("{}", obj.end());
+    } else {
+        let mut s = format!("info depth {}", depth);
+        if let Some(v) = value {
+            s.push_str(&format!(" score {}", uci::value(v)));
+        }
+        s.push_str(&format!(" nodes {} nps {} time {}", nodes, nps, elapsed));
+        if value.is_some() {
+            s.push_str(" pv ");
+            s.push_str(&pv.join(" "));
+        }
+        println!("{}", s);
+    }
+    stdout().flush().unwrap();
+}
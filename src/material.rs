@@ -140,9 +140,14 @@ fn imbalance(pc: &[[i32; 6]; 2], us: Color) -> i32 {
 
 pub fn probe(pos: &Position) -> &'static mut Entry {
     let key = pos.material_key();
-    let e = pos.material_table[(key.0 & 8191) as usize].get();
+    let e = pos.material_table
+        [key.0 as usize & (pos.material_table.len() - 1)].get();
     let e: &'static mut Entry = unsafe { &mut *e };
 
+    let mut stats = pos.table_stats.get();
+    stats.material_probes += 1;
+    stats.material_hits += (e.key == key) as u64;
+    pos.table_stats.set(stats);
     if e.key == key {
         return e;
     }
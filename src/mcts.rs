@@ -0,0 +1,434 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use movegen::*;
+use position::Position;
+use search;
+use search::Stack;
+use threads;
+use timeman;
+use types::*;
+use ucioption;
+
+use std;
+use std::sync::Mutex;
+
+// With the "SearchMode" option set to MCTS the threads run a Monte-Carlo
+// tree search instead of alpha-beta. All threads work on one shared tree.
+// Each playout walks down the tree choosing children by PUCT, evaluates the
+// leaf with a quiescence search whose value is mapped to a win probability,
+// and adds the result to the statistics of the nodes on its path. While a
+// playout is under way its path carries a virtual loss, so that the other
+// threads are steered to other lines.
+//
+// The move priors come from cheap move features (captures, promotions,
+// checks, SEE and the history tables) instead of a policy network.
+
+// Exploration constant of PUCT
+const CPUCT: f64 = 1.5;
+
+// First play urgency: unvisited children are assumed to be this much worse
+// than their parent
+const FPU_REDUCTION: f64 = 0.2;
+
+// How many pawns make a difference of a factor of 10 in the odds to win
+const PAWNS_PER_DECADE: f64 = 4.0;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Leaf,
+    Expanded,
+    Terminal(f64), // Result for the side that moved into the node
+}
+
+struct Node {
+    m: Move,
+    prior: f32,
+    visits: u32,
+    virtual_loss: u32,
+    value: f64, // Sum of results for the side that moved into the node
+    first_child: u32,
+    num_children: u32,
+    state: State,
+}
+
+impl Node {
+    fn new(m: Move, prior: f32) -> Node {
+        Node {
+            m,
+            prior,
+            visits: 0,
+            virtual_loss: 0,
+            value: 0.0,
+            first_child: 0,
+            num_children: 0,
+            state: State::Leaf,
+        }
+    }
+
+    fn children(&self) -> std::ops::Range<usize> {
+        self.first_child as usize
+            ..(self.first_child + self.num_children) as usize
+    }
+
+    // q() returns the average result for the side that moved into the node
+    // counting virtual losses, or None for an unvisited node.
+    fn q(&self) -> Option<f64> {
+        let n = self.visits + self.virtual_loss;
+        if n == 0 { None } else { Some(self.value / n as f64) }
+    }
+}
+
+struct Tree {
+    nodes: Vec<Node>,
+    max_nodes: usize,
+    playouts: u64,
+    depth_sum: u64,
+    max_depth: usize,
+}
+
+impl Tree {
+    fn avg_depth(&self) -> u64 {
+        self.depth_sum.checked_div(self.playouts).unwrap_or(0)
+    }
+}
+
+static TREE: Mutex<Tree> = Mutex::new(Tree {
+    nodes: Vec::new(),
+    max_nodes: 0,
+    playouts: 0,
+    depth_sum: 0,
+    max_depth: 0,
+});
+
+// clear() drops the tree of the last search. It is called before the search
+// threads are started.
+
+pub fn clear() {
+    let mut tree = TREE.lock().unwrap();
+    tree.nodes = Vec::new();
+    tree.playouts = 0;
+    tree.depth_sum = 0;
+    tree.max_depth = 0;
+}
+
+pub fn enabled() -> bool {
+    ucioption::get_string("SearchMode") == "mcts"
+}
+
+// win_probability() maps a search value for the side to move to the
+// probability to win, with mates as certain results.
+
+fn win_probability(v: Value) -> f64 {
+    if v >= Value::MATE_IN_MAX_PLY {
+        return 1.0;
+    }
+    if v <= Value::MATED_IN_MAX_PLY {
+        return 0.0;
+    }
+    let pawns = v.0 as f64 / PawnValueEg.0 as f64;
+    1.0 / (1.0 + 10f64.powf(-pawns / PAWNS_PER_DECADE))
+}
+
+// value_of() is the inverse of win_probability(), used to report scores
+
+fn value_of(p: f64) -> Value {
+    let p = p.clamp(0.001, 0.999);
+    let pawns = PAWNS_PER_DECADE * (p / (1.0 - p)).log10();
+    Value((pawns * PawnValueEg.0 as f64) as i32)
+}
+
+// priors() returns the prior probabilities of the moves as a softmax over a
+// heuristic score of each move.
+
+fn priors(pos: &Position, moves: &[Move]) -> Vec<f32> {
+    let us = pos.side_to_move();
+    let scores: Vec<f64> = moves.iter().map(|&m| {
+        let mut h = 0.0;
+        if pos.capture(m) {
+            h += piece_value(EG, pos.piece_on(m.to())).0 as f64
+                / PawnValueEg.0 as f64;
+        }
+        if m.move_type() == PROMOTION {
+            h += 2.0;
+        }
+        if pos.gives_check(m) {
+            h += 0.5;
+        }
+        if !pos.see_ge(m, Value::ZERO) {
+            h -= 1.0;
+        }
+        h + pos.main_history.get(us, m) as f64 / 8000.0
+    }).collect();
+
+    let max = scores.iter().cloned().fold(f64::MIN, f64::max);
+    let exps: Vec<f64> = scores.iter().map(|&s| (s - max).exp()).collect();
+    let sum: f64 = exps.iter().sum();
+    exps.iter().map(|&e| (e / sum) as f32).collect()
+}
+
+// expand() adds the children of a leaf unless the tree is full. Another
+// thread may have expanded the node in the meantime.
+
+fn expand(tree: &mut Tree, idx: usize, children: &[(Move, f32)]) {
+    if tree.nodes[idx].state != State::Leaf
+        || tree.nodes.len() + children.len() > tree.max_nodes
+    {
+        return;
+    }
+    tree.nodes[idx].first_child = tree.nodes.len() as u32;
+    tree.nodes[idx].num_children = children.len() as u32;
+    tree.nodes[idx].state = State::Expanded;
+    for &(m, prior) in children {
+        tree.nodes.push(Node::new(m, prior));
+    }
+}
+
+// select() walks down from the root to a leaf, choosing the child with the
+// highest PUCT score at each node, and adds a virtual loss to the path.
+
+fn select(tree: &mut Tree, path: &mut Vec<usize>) {
+    let mut idx = 0;
+    path.push(idx);
+    tree.nodes[idx].virtual_loss += 1;
+
+    while tree.nodes[idx].state == State::Expanded {
+        let parent = &tree.nodes[idx];
+        let sqrt_n = ((parent.visits + parent.virtual_loss) as f64).sqrt();
+
+        // The result for the side to move here is the complement of the
+        // result for the side that moved into the node
+        let fpu = parent.q().map_or(0.5, |q| 1.0 - q) - FPU_REDUCTION;
+
+        let mut best = idx;
+        let mut best_score = f64::MIN;
+        for c in parent.children() {
+            let child = &tree.nodes[c];
+            let n = (child.visits + child.virtual_loss) as f64;
+            let score = child.q().unwrap_or(fpu)
+                + CPUCT * child.prior as f64 * sqrt_n / (1.0 + n);
+            if score > best_score {
+                best_score = score;
+                best = c;
+            }
+        }
+
+        idx = best;
+        path.push(idx);
+        tree.nodes[idx].virtual_loss += 1;
+    }
+}
+
+// backup() adds the result of a playout to the nodes on its path and
+// removes the virtual loss. The result alternates between the two sides.
+
+fn backup(tree: &mut Tree, path: &[usize], mut result: f64) {
+    for &idx in path.iter().rev() {
+        let node = &mut tree.nodes[idx];
+        node.visits += 1;
+        node.virtual_loss -= 1;
+        node.value += result;
+        result = 1.0 - result;
+    }
+    tree.playouts += 1;
+    tree.depth_sum += (path.len() - 1) as u64;
+    tree.max_depth = std::cmp::max(tree.max_depth, path.len() - 1);
+}
+
+// evaluate() returns the result of the leaf for the side that moved into
+// it, whether the result is final, and the children to expand the leaf
+// with.
+
+fn evaluate(
+    pos: &mut Position, ss: &mut [Stack], ply: usize, last: Move
+) -> (f64, bool, Vec<(Move, f32)>) {
+    if pos.is_draw(ply as i32) {
+        return (0.5, true, Vec::new());
+    }
+
+    let moves: Vec<Move> = MoveList::new::<Legal>(pos).collect();
+    if moves.is_empty() {
+        return (if pos.checkers() != 0 { 1.0 } else { 0.5 }, true,
+            Vec::new());
+    }
+
+    let v = search::leaf_value(pos, ss, ply as i32, last);
+    let priors = priors(pos, &moves);
+    (1.0 - win_probability(v), false,
+        moves.into_iter().zip(priors).collect())
+}
+
+// pv() follows the most visited children from the given node
+
+fn pv(tree: &Tree, mut idx: usize) -> Vec<Move> {
+    let mut pv = vec![tree.nodes[idx].m];
+    while tree.nodes[idx].state == State::Expanded {
+        let best = tree.nodes[idx].children()
+            .max_by_key(|&c| tree.nodes[c].visits).unwrap();
+        if tree.nodes[best].visits == 0 {
+            break;
+        }
+        pv.push(tree.nodes[best].m);
+        idx = best;
+    }
+    pv
+}
+
+// update_root_moves() sets the scores and PV lines of the root moves from
+// the tree and sorts them by their number of visits. It returns the average
+// depth of the playouts.
+
+fn update_root_moves(pos: &mut Position) -> Depth {
+    let tree = TREE.lock().unwrap();
+    if tree.nodes.is_empty() {
+        return Depth::ZERO;
+    }
+    let root = &tree.nodes[0];
+    let fpu = root.q().map_or(0.5, |q| 1.0 - q) - FPU_REDUCTION;
+
+    let mut visits = Vec::new();
+    for c in root.children() {
+        let child = &tree.nodes[c];
+        let rm = pos.root_moves.iter_mut().find(|rm| rm.pv[0] == child.m);
+        if let Some(rm) = rm {
+            rm.score = match child.state {
+                State::Terminal(1.0) => mate_in(1),
+                State::Terminal(_) => Value::DRAW,
+                _ => value_of(child.q().unwrap_or(fpu)),
+            };
+            rm.previous_score = rm.score;
+            rm.sel_depth = tree.max_depth as i32 - 1;
+            rm.pv = pv(&tree, c);
+            visits.push((child.m, child.visits));
+        }
+    }
+    let visits_of = |m: Move| visits.iter().find(|v| v.0 == m)
+        .map_or(0, |v| v.1);
+    pos.root_moves.sort_by(|a, b| visits_of(b.pv[0]).cmp(&visits_of(a.pv[0])));
+
+    std::cmp::max(tree.avg_depth() as i32, 1) * ONE_PLY
+}
+
+// search() runs playouts until the search is stopped. The main thread
+// reports the PV lines about once per second, stops the search when the
+// limits are reached and finally leaves the root moves ordered by visits.
+
+pub fn search(pos: &mut Position, ss: &mut [Stack]) {
+    {
+        let mut tree = TREE.lock().unwrap();
+        if tree.nodes.is_empty() {
+            let mb = ucioption::get_i32("MCTS Memory") as usize;
+            tree.max_nodes = mb * 1024 * 1024 / std::mem::size_of::<Node>();
+            tree.nodes.push(Node::new(Move::NONE, 1.0));
+            let moves: Vec<Move> =
+                pos.root_moves.iter().map(|rm| rm.pv[0]).collect();
+            let children: Vec<(Move, f32)> =
+                moves.iter().cloned().zip(priors(pos, &moves)).collect();
+            expand(&mut tree, 0, &children);
+        }
+    }
+
+    let mut path: Vec<usize> = Vec::new();
+    let mut moves: Vec<Move> = Vec::new();
+    let mut playouts = 0u64;
+    let mut last_report = 0;
+
+    while !threads::stop() {
+        path.clear();
+        moves.clear();
+        {
+            let mut tree = TREE.lock().unwrap();
+            select(&mut tree, &mut path);
+            for &idx in path[1..].iter() {
+                moves.push(tree.nodes[idx].m);
+            }
+        }
+
+        for &m in moves.iter() {
+            let gives_check = pos.gives_check(m);
+            pos.do_move(m, gives_check);
+        }
+
+        let leaf = *path.last().unwrap();
+        let terminal = match TREE.lock().unwrap().nodes[leaf].state {
+            State::Terminal(result) => Some(result),
+            _ => None,
+        };
+        let (result, is_final, children) = match terminal {
+            Some(result) => (result, true, Vec::new()),
+            None => evaluate(pos, ss, moves.len(),
+                moves.last().cloned().unwrap_or(Move::NONE)),
+        };
+
+        for &m in moves.iter().rev() {
+            pos.undo_move(m);
+        }
+
+        {
+            let mut tree = TREE.lock().unwrap();
+            if is_final {
+                tree.nodes[leaf].state = State::Terminal(result);
+            } else {
+                expand(&mut tree, leaf, &children);
+            }
+            backup(&mut tree, &path, result);
+        }
+
+        playouts += 1;
+        if !playouts.is_multiple_of(16) {
+            continue;
+        }
+
+        search::update_counters(pos);
+        search::check_time(pos);
+
+        if !pos.is_main {
+            continue;
+        }
+
+        let elapsed = timeman::elapsed();
+        let (depth, mate_found) = {
+            let tree = TREE.lock().unwrap();
+            (tree.avg_depth() as u32,
+            tree.nodes[0].children()
+                .any(|c| tree.nodes[c].state == State::Terminal(1.0)))
+        };
+
+        // Stop at the optimum time, when the average depth of the playouts
+        // reaches the depth limit or when there is a mate in one
+        if (mate_found || limits_reached(elapsed, depth))
+            && !threads::stop()
+        {
+            if threads::ponder() {
+                threads::set_stop_on_ponderhit(true);
+            } else {
+                threads::set_stop(true);
+            }
+        }
+
+        if elapsed - last_report >= 1000 {
+            last_report = elapsed;
+            report(pos);
+        }
+    }
+
+    if pos.is_main {
+        search::update_counters(pos);
+        report(pos);
+    }
+}
+
+fn limits_reached(elapsed: i64, depth: u32) -> bool {
+    let limits = search::limits();
+    (limits.use_time_management()
+        && elapsed - timeman::pondered() > timeman::optimum())
+        || (limits.depth != 0 && depth >= limits.depth)
+}
+
+fn report(pos: &mut Position) {
+    let depth = update_root_moves(pos);
+    pos.completed_depth = depth;
+    let multi_pv = std::cmp::min(ucioption::get_i32("MultiPV") as usize,
+        pos.root_moves.len());
+    pos.pv_idx = multi_pv - 1;
+    search::print_pv(pos, depth, -Value::INFINITE, Value::INFINITE);
+}
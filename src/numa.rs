@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std;
+use std::fs;
+
+// Thread binding policies, selected with the "Thread Binding" option:
+//
+//  none     threads are not bound, placement is left to the OS
+//  compact  thread i is bound to the i-th CPU, filling one NUMA node first
+//  scatter  threads are spread round-robin over the NUMA nodes and bound to
+//           all CPUs of their node
+//  <list>   thread i is bound to the i-th CPU of an explicit list such as
+//           "0-7,16-23"
+//
+// Binding is done by each search thread before it allocates its position,
+// pawn and material tables and history tables. With Linux's first-touch
+// policy that memory is then allocated on the thread's own NUMA node.
+
+pub enum Binding {
+    Unbound,
+    Compact,
+    Scatter,
+    Explicit(Vec<usize>),
+}
+
+impl Binding {
+    pub fn parse(s: &str) -> Option<Binding> {
+        match s.trim().to_lowercase().as_ref() {
+            "" | "none" | "<empty>" => Some(Binding::Unbound),
+            "compact" => Some(Binding::Compact),
+            "scatter" => Some(Binding::Scatter),
+            list => parse_cpu_list(list).map(Binding::Explicit),
+        }
+    }
+}
+
+// parse_cpu_list() parses a list of CPUs in the format used by the Linux
+// kernel, e.g. "0-3,8,10-11".
+
+fn parse_cpu_list(s: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+
+    for range in s.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut bounds = range.splitn(2, '-');
+        let first: usize = bounds.next()?.trim().parse().ok()?;
+        let last: usize = match bounds.next() {
+            Some(b) => b.trim().parse().ok()?,
+            None => first,
+        };
+        if last < first {
+            return None;
+        }
+        cpus.extend(first..last + 1);
+    }
+
+    if cpus.is_empty() { None } else { Some(cpus) }
+}
+
+// nodes() returns the CPUs of each NUMA node as reported by sysfs. Without
+// NUMA information all available CPUs form a single node.
+
+pub fn nodes() -> Vec<Vec<usize>> {
+    let mut nodes: Vec<(usize, Vec<usize>)> = Vec::new();
+
+    if let Ok(entries) = fs::read_dir("/sys/devices/system/node") {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with("node") {
+                continue;
+            }
+            let idx = match name[4..].parse() {
+                Ok(idx) => idx,
+                Err(_) => continue,
+            };
+            let cpus = fs::read_to_string(entry.path().join("cpulist")).ok()
+                .and_then(|s| parse_cpu_list(&s));
+            if let Some(cpus) = cpus {
+                nodes.push((idx, cpus));
+            }
+        }
+    }
+
+    if nodes.is_empty() {
+        let n = std::thread::available_parallelism().map(|n| n.get())
+            .unwrap_or(1);
+        return vec![(0..n).collect()];
+    }
+
+    nodes.sort();
+    nodes.into_iter().map(|(_, cpus)| cpus).collect()
+}
+
+// cpus_for_thread() returns the set of CPUs that search thread 'idx' should
+// be bound to, or None if it should not be bound.
+
+pub fn cpus_for_thread(binding: &Binding, idx: usize) -> Option<Vec<usize>> {
+    match *binding {
+        Binding::Unbound => None,
+        Binding::Compact => {
+            let cpus: Vec<usize> = nodes().into_iter().flatten().collect();
+            Some(vec![cpus[idx % cpus.len()]])
+        }
+        Binding::Scatter => {
+            let nodes = nodes();
+            Some(nodes[idx % nodes.len()].clone())
+        }
+        Binding::Explicit(ref cpus) => Some(vec![cpus[idx % cpus.len()]]),
+    }
+}
+
+// bind() binds the calling thread to the given CPUs. It returns false if
+// the operating system refused the binding or does not support it.
+
+#[cfg(target_os = "linux")]
+pub fn bind(cpus: &[usize]) -> bool {
+    use libc;
+
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        for &cpu in cpus {
+            if cpu < libc::CPU_SETSIZE as usize {
+                libc::CPU_SET(cpu, &mut set);
+            }
+        }
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(),
+            &set) == 0
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind(_cpus: &[usize]) -> bool {
+    false
+}
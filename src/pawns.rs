@@ -281,9 +281,14 @@ pub fn init() {
 
 pub fn probe(pos: &Position) -> &mut Entry {
     let key = pos.pawn_key();
-    let e = pos.pawns_table[(key.0 & 16383) as usize].get();
+    let e = pos.pawns_table[key.0 as usize & (pos.pawns_table.len() - 1)]
+        .get();
     let e: &'static mut Entry = unsafe { &mut *e };
 
+    let mut stats = pos.table_stats.get();
+    stats.pawns_probes += 1;
+    stats.pawns_hits += (e.key == key) as u64;
+    pos.table_stats.set(stats);
     if e.key == key {
         return e;
     }
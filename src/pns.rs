@@ -0,0 +1,543 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use bitboard::*;
+use json;
+use movegen::*;
+use position::Position;
+use tb;
+use types::*;
+use uci;
+use ucioption;
+
+use std;
+use std::fs::File;
+use std::io::Write;
+use std::time::Instant;
+
+// Proof-number search proves or disproves a goal for the side to move at the
+// root, here that it wins or that it at least draws. Unlike alpha-beta it
+// searches no fixed depth: it always expands the most-proving leaf, the one
+// whose proof contributes most to proving or disproving the root.
+//
+// OR nodes, where the root side is to move, need one proven child; AND
+// nodes, where the opponent is to move, need all children proven. Their
+// proof number pn and disproof number dn are
+//
+//   OR:  pn = min(pn of children)  dn = sum(dn of children)
+//   AND: pn = sum(pn of children)  dn = min(dn of children)
+//
+// Checkmate, stalemate, draws by repetition or the 50-move rule and, when
+// available, the Syzygy WDL tables are terminal.
+//
+// With PN² every leaf is evaluated by a second-level proof-number search of
+// up to as many nodes as the first-level tree has, whose tree is discarded
+// afterwards. This finds deeper proofs within the same memory.
+
+const INFINITE: u32 = u32::MAX;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Outcome { Loss, Draw, Win }
+
+impl Outcome {
+    fn name(self) -> &'static str {
+        match self {
+            Outcome::Loss => "loss",
+            Outcome::Draw => "draw",
+            Outcome::Win => "win",
+        }
+    }
+}
+
+struct Node {
+    m: Move,
+    parent: u32,
+    first_child: u32,
+    children: u32,
+    expanded: bool,
+    pn: u32,
+    dn: u32,
+    terminal: Option<Outcome>,
+}
+
+// Tree is the node table of one proof-number search. The children of a node
+// are stored next to each other.
+
+struct Tree {
+    nodes: Vec<Node>,
+    root_ply: i32,
+    goal: Outcome,
+}
+
+// Search holds what all trees of a search share: the side that has to reach
+// the goal, the outcome it must at least reach, the node limits and the
+// limits on the nodes visited and the time used, 0 meaning no limit.
+
+struct Search {
+    us: Color,
+    goal: Outcome,
+    max_nodes: usize,
+    pn2: bool,
+    visited: u64,
+    max_visited: u64,
+    start: Instant,
+    max_time: u64,
+}
+
+impl Tree {
+    fn new(root_ply: i32, goal: Outcome) -> Tree {
+        Tree {
+            nodes: vec![Node {
+                m: Move::NONE,
+                parent: 0,
+                first_child: 0,
+                children: 0,
+                expanded: false,
+                pn: 1,
+                dn: 1,
+                terminal: None,
+            }],
+            root_ply,
+            goal,
+        }
+    }
+
+    fn solved(&self) -> bool {
+        self.nodes[0].pn == 0 || self.nodes[0].dn == 0
+    }
+
+    fn children(&self, idx: usize) -> std::ops::Range<usize> {
+        let first = self.nodes[idx].first_child as usize;
+        first..first + self.nodes[idx].children as usize
+    }
+
+    // update() recalculates the proof and disproof numbers of a node from
+    // its children
+    fn update(&mut self, idx: usize, or_node: bool) {
+        let (mut min, mut sum) = (INFINITE, 0u32);
+        for c in self.children(idx) {
+            let (a, b) = if or_node { (self.nodes[c].pn, self.nodes[c].dn) }
+                else { (self.nodes[c].dn, self.nodes[c].pn) };
+            min = std::cmp::min(min, a);
+            sum = sum.saturating_add(b);
+        }
+        let node = &mut self.nodes[idx];
+        if or_node {
+            node.pn = min;
+            node.dn = sum;
+        } else {
+            node.dn = min;
+            node.pn = sum;
+        }
+    }
+}
+
+impl Search {
+    // terminal() returns the outcome for the root side if the game is over
+    // or the position is in the tablebases.
+
+    fn terminal(&self, pos: &mut Position, ply: i32) -> Option<Outcome> {
+        let them_to_move = pos.side_to_move() != self.us;
+        let from_us = |o: Outcome| {
+            if !them_to_move { o }
+            else if o == Outcome::Win { Outcome::Loss }
+            else if o == Outcome::Loss { Outcome::Win }
+            else { o }
+        };
+
+        if ply > 0 && pos.is_draw(ply) {
+            return Some(Outcome::Draw);
+        }
+
+        if MoveList::new::<Legal>(pos).len() == 0 {
+            return Some(if pos.checkers() != 0 { from_us(Outcome::Loss) }
+                else { Outcome::Draw });
+        }
+
+        // Neither side can mate with a lone king or a king and a minor piece
+        let pieces_cnt = popcount(pos.pieces());
+        if pieces_cnt == 2
+            || (pieces_cnt == 3 && pos.pieces_pp(KNIGHT, BISHOP) != 0)
+        {
+            return Some(Outcome::Draw);
+        }
+
+        if tb::cardinality() != 0
+            && pieces_cnt <= tb::cardinality()
+            && pos.rule50_count() == 0
+            && !pos.has_castling_right(ANY_CASTLING)
+        {
+            let mut found = 1;
+            let wdl = tb::probe_wdl(pos, &mut found);
+            if found != 0 {
+                let draw_score = if tb::use_rule_50() { 1 } else { 0 };
+                let outcome = if wdl > draw_score { Outcome::Win }
+                    else if wdl < -draw_score { Outcome::Loss }
+                    else { Outcome::Draw };
+                return Some(from_us(outcome));
+            }
+        }
+
+        None
+    }
+
+    // init_leaf() sets the proof and disproof numbers of a new leaf. Leaves
+    // with many moves are harder to prove where all moves must be refuted.
+
+    fn init_leaf(
+        &mut self, tree: &mut Tree, idx: usize, pos: &mut Position, ply: i32
+    ) {
+        self.visited += 1;
+
+        let terminal = self.terminal(pos, ply);
+        let node = &mut tree.nodes[idx];
+        node.terminal = terminal;
+
+        match terminal {
+            Some(outcome) if outcome >= self.goal => {
+                node.pn = 0;
+                node.dn = INFINITE;
+            }
+            Some(_) => {
+                node.pn = INFINITE;
+                node.dn = 0;
+            }
+            None => {
+                let moves = MoveList::new::<Legal>(pos).len() as u32;
+                if pos.side_to_move() == self.us {
+                    node.pn = 1;
+                    node.dn = moves;
+                } else {
+                    node.pn = moves;
+                    node.dn = 1;
+                }
+            }
+        }
+    }
+
+    // expand() adds the children of a leaf. With PN² their proof and
+    // disproof numbers come from a second-level search.
+
+    fn expand(
+        &mut self, tree: &mut Tree, idx: usize, pos: &mut Position, ply: i32,
+        second_level: bool
+    ) {
+        let first_child = tree.nodes.len() as u32;
+        let parent_limit = tree.nodes.len();
+
+        for m in MoveList::new::<Legal>(pos) {
+            let c = tree.nodes.len();
+            tree.nodes.push(Node {
+                m,
+                parent: idx as u32,
+                first_child: 0,
+                children: 0,
+                expanded: false,
+                pn: 1,
+                dn: 1,
+                terminal: None,
+            });
+
+            let gives_check = pos.gives_check(m);
+            pos.do_move(m, gives_check);
+            self.init_leaf(tree, c, pos, ply + 1);
+
+            if second_level && tree.nodes[c].terminal.is_none() {
+                let mut sub = Tree::new(ply + 1, self.goal);
+                self.init_leaf(&mut sub, 0, pos, ply + 1);
+                let limit = std::cmp::min(
+                    std::cmp::max(parent_limit, 1024), self.max_nodes);
+                self.run(&mut sub, pos, limit, false);
+                tree.nodes[c].pn = sub.nodes[0].pn;
+                tree.nodes[c].dn = sub.nodes[0].dn;
+            }
+
+            pos.undo_move(m);
+        }
+
+        let children = tree.nodes.len() as u32 - first_child;
+        let node = &mut tree.nodes[idx];
+        node.first_child = first_child;
+        node.children = children;
+        node.expanded = true;
+    }
+
+    // run() expands the most-proving leaf until the root of the tree is
+    // solved or the tree has 'limit' nodes. It returns whether the root was
+    // solved.
+
+    fn run(
+        &mut self, tree: &mut Tree, pos: &mut Position, limit: usize,
+        pn2: bool
+    ) -> bool {
+        let mut path = Vec::new();
+
+        while !tree.solved() && tree.nodes.len() < limit && !self.stopped() {
+            // Select the most-proving leaf
+            let mut idx = 0;
+            let mut ply = tree.root_ply;
+            while tree.nodes[idx].expanded {
+                let or_node = pos.side_to_move() == self.us;
+                let target = if or_node { tree.nodes[idx].pn }
+                    else { tree.nodes[idx].dn };
+                idx = tree.children(idx).find(|&c|
+                    if or_node { tree.nodes[c].pn == target }
+                    else { tree.nodes[c].dn == target }).unwrap();
+                let m = tree.nodes[idx].m;
+                let gives_check = pos.gives_check(m);
+                pos.do_move(m, gives_check);
+                path.push(m);
+                ply += 1;
+            }
+
+            self.expand(tree, idx, pos, ply, pn2);
+
+            // Update the ancestors on the way back to the root
+            loop {
+                let or_node = pos.side_to_move() == self.us;
+                tree.update(idx, or_node);
+                if idx == 0 {
+                    break;
+                }
+                idx = tree.nodes[idx].parent as usize;
+                pos.undo_move(path.pop().unwrap());
+            }
+        }
+
+        tree.solved()
+    }
+
+    // stopped() returns true if the search has reached its limit of visited
+    // nodes or time. The search runs on the UI thread, so "stop" cannot
+    // end it.
+
+    fn stopped(&self) -> bool {
+        (self.max_visited != 0 && self.visited >= self.max_visited)
+            || (self.max_time != 0
+                && uci::elapsed_ms(self.start) >= self.max_time)
+    }
+
+    // prove() runs a search for the goal from the current position. It
+    // returns the tree, with a solved root if the goal could be proven or
+    // disproven within the node limit.
+
+    fn prove(&mut self, pos: &mut Position, goal: Outcome) -> Tree {
+        self.goal = goal;
+        let mut tree = Tree::new(0, goal);
+        self.init_leaf(&mut tree, 0, pos, 0);
+        let limit = self.max_nodes;
+        let pn2 = self.pn2;
+        self.run(&mut tree, pos, limit, pn2);
+        tree
+    }
+}
+
+impl Search {
+    // resolve() returns a new tree for a leaf that was solved by a
+    // second-level search, whose tree was discarded, by proving it again.
+
+    fn resolve(
+        &mut self, tree: &Tree, idx: usize, pos: &mut Position, ply: i32
+    ) -> Option<Tree> {
+        let node = &tree.nodes[idx];
+        if node.expanded || node.terminal.is_some() {
+            return None;
+        }
+
+        self.goal = tree.goal;
+        let mut sub = Tree::new(ply, tree.goal);
+        self.init_leaf(&mut sub, 0, pos, ply);
+        let limit = self.max_nodes;
+        if self.run(&mut sub, pos, limit, false) { Some(sub) } else { None }
+    }
+
+    // solving_children() returns the children of a solved node that are
+    // part of its proof or disproof. Where one child suffices only the
+    // first one is returned.
+
+    fn solving_children(
+        &self, tree: &Tree, idx: usize, pos: &Position
+    ) -> Vec<usize> {
+        let node = &tree.nodes[idx];
+        let or_node = pos.side_to_move() == self.us;
+        let needs_one = (node.pn == 0) == or_node;
+        let solving: Vec<usize> = tree.children(idx)
+            .filter(|&c| if node.pn == 0 { tree.nodes[c].pn == 0 }
+                else { tree.nodes[c].dn == 0 })
+            .collect();
+        if needs_one { solving.into_iter().take(1).collect() } else { solving }
+    }
+
+    // export() converts the principal proof or disproof tree below a solved
+    // node into JSON.
+
+    fn export(
+        &mut self, tree: &Tree, idx: usize, pos: &mut Position, ply: i32
+    ) -> String {
+        let node = &tree.nodes[idx];
+        let mut obj = json::Object::new("node");
+        if node.m != Move::NONE {
+            obj = obj.str("move", &uci::move_str(node.m, pos.is_chess960()));
+        }
+        obj = obj.str("status",
+            if node.pn == 0 { "proven" } else { "disproven" });
+        if let Some(outcome) = node.terminal {
+            obj = obj.str("result", outcome.name());
+        }
+
+        let children = self.export_children(tree, idx, pos, ply);
+        if !children.is_empty() {
+            obj = obj.raw("children", &format!("[{}]", children.join(",")));
+        }
+        obj.end()
+    }
+
+    fn export_children(
+        &mut self, tree: &Tree, idx: usize, pos: &mut Position, ply: i32
+    ) -> Vec<String> {
+        if let Some(sub) = self.resolve(tree, idx, pos, ply) {
+            return self.export_children(&sub, 0, pos, ply);
+        }
+
+        let mut children = Vec::new();
+        for c in self.solving_children(tree, idx, pos) {
+            let m = tree.nodes[c].m;
+            let gives_check = pos.gives_check(m);
+            pos.do_move(m, gives_check);
+            children.push(self.export(tree, c, pos, ply + 1));
+            pos.undo_move(m);
+        }
+        children
+    }
+
+    // principal_line() follows the proof tree from a solved node: the
+    // proving move where one move suffices, otherwise the move with the most
+    // children, which is usually the most stubborn defence.
+
+    fn principal_line(
+        &mut self, tree: &Tree, idx: usize, pos: &mut Position, ply: i32
+    ) -> Vec<Move> {
+        if let Some(sub) = self.resolve(tree, idx, pos, ply) {
+            return self.principal_line(&sub, 0, pos, ply);
+        }
+
+        let next = self.solving_children(tree, idx, pos).into_iter()
+            .max_by_key(|&c| tree.nodes[c].children);
+        match next {
+            Some(c) => {
+                let m = tree.nodes[c].m;
+                let gives_check = pos.gives_check(m);
+                pos.do_move(m, gives_check);
+                let mut line = vec![m];
+                line.extend(self.principal_line(tree, c, pos, ply + 1));
+                pos.undo_move(m);
+                line
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+// run() is called when the engine receives the "pns" command. It proves the
+// outcome of the current position with a memory limit of "PN Memory" MB:
+// first whether the side to move wins, and if not, whether it draws.
+// Arguments are "pn2" to use PN², "export <file>" to write the principal
+// proof tree to a file, and "nodes <n>" and "movetime <ms>" to limit the
+// nodes visited and the time used. Without a proof in time, the result is
+// unknown.
+
+pub fn run(pos: &mut Position, args: &str) {
+    let mut pn2 = false;
+    let mut export_file = None;
+    let mut max_visited = 0;
+    let mut max_time = 0;
+    let mut iter = args.split_whitespace();
+    while let Some(token) = iter.next() {
+        match token {
+            "pn2" => pn2 = true,
+            "export" => export_file = iter.next().map(String::from),
+            "nodes" => max_visited = iter.next()
+                .and_then(|n| n.parse().ok()).unwrap_or(0),
+            "movetime" => max_time = iter.next()
+                .and_then(|n| n.parse().ok()).unwrap_or(0),
+            _ => {}
+        }
+    }
+
+    tb::read_options();
+
+    let start = Instant::now();
+    let mut search = Search {
+        us: pos.side_to_move(),
+        goal: Outcome::Win,
+        max_nodes: ucioption::get_i32("PN Memory") as usize * 1024 * 1024
+            / std::mem::size_of::<Node>(),
+        pn2,
+        visited: 0,
+        max_visited,
+        start,
+        max_time,
+    };
+
+    // The tree that decides the result, and for a draw the tree that shows
+    // that we cannot win
+    let mut trees = Vec::new();
+    let win = search.prove(pos, Outcome::Win);
+    let result = if !win.solved() {
+        trees.push(win);
+        None
+    } else if win.nodes[0].pn == 0 {
+        trees.push(win);
+        Some(Outcome::Win)
+    } else {
+        let draw = search.prove(pos, Outcome::Draw);
+        let result = if !draw.solved() { None }
+            else if draw.nodes[0].pn == 0 { Some(Outcome::Draw) }
+            else { Some(Outcome::Loss) };
+        trees.push(draw);
+        if result == Some(Outcome::Draw) {
+            trees.push(win);
+        }
+        result
+    };
+
+    // Proofs are re-searched for the line and the export, which must not
+    // be cut short by the limits
+    search.max_visited = 0;
+    search.max_time = 0;
+
+    let name = result.map_or("unknown", |r| r.name());
+    let line: Vec<String> = if result.is_some() {
+        search.principal_line(&trees[0], 0, pos, 0).iter()
+            .map(|&m| uci::move_str(m, pos.is_chess960())).collect()
+    } else {
+        Vec::new()
+    };
+    let ms = uci::elapsed_ms(start);
+
+    if uci::json() {
+        println!("{}", json::Object::new("pns").str("result", name)
+            .num("nodes", search.visited).num("time", ms).strs("pv", &line)
+            .end());
+    } else {
+        println!("info string pns result {} nodes {} time {} pv {}", name,
+            search.visited, ms, line.join(" "));
+    }
+
+    if let Some(file) = export_file {
+        if result.is_none() {
+            println!("info string Nothing proven, no proof tree exported");
+            return;
+        }
+        let mut obj = json::Object::new("proof").str("fen", &pos.fen())
+            .str("result", name)
+            .raw("tree", &search.export(&trees[0], 0, pos, 0));
+        if trees.len() > 1 {
+            obj = obj.raw("no_win", &search.export(&trees[1], 0, pos, 0));
+        }
+        let written = File::create(&file)
+            .and_then(|mut f| writeln!(f, "{}", obj.end()));
+        if written.is_err() {
+            println!("info string Unable to write proof tree to {}", file);
+        }
+    }
+}
@@ -8,11 +8,13 @@ use pawns;
 use psqt;
 use search;
 use tb;
+use threads;
 use threads::ThreadCtrl;
 use types::*;
 use uci;
 
 use std;
+use std::cell::Cell;
 use std::sync::Arc;
 
 pub mod zobrist {
@@ -166,6 +168,7 @@ pub struct Position {
     pub previous_time_reduction: f64,
     pub previous_score: Value,
     pub calls_cnt: i32,
+    pub last_info_time: i64,
     // for all threads:
     pub thread_ctrl: Option<Arc<ThreadCtrl>>,
     pub is_main: bool,
@@ -182,6 +185,9 @@ pub struct Position {
     // thread-specific tables
     pub pawns_table: Vec<std::cell::UnsafeCell<pawns::Entry>>,
     pub material_table: Vec<std::cell::UnsafeCell<material::Entry>>,
+    pub table_stats: Cell<threads::TableStats>,
+    #[cfg(feature = "searchstats")]
+    pub stats: search::SearchStats,
     pub counter_moves: CounterMoveHistory,
     pub main_history: ButterflyHistory,
     pub capture_history: CapturePieceToHistory,
@@ -209,6 +215,7 @@ impl Position {
             previous_time_reduction: 0.0,
             previous_score: Value::ZERO,
             calls_cnt: 0,
+            last_info_time: 0,
             thread_ctrl: None,
             is_main: false,
             thread_idx: 0,
@@ -223,6 +230,9 @@ impl Position {
             root_moves: Vec::new(),
             pawns_table: Vec::new(),
             material_table: Vec::new(),
+            table_stats: Cell::new(threads::TableStats::default()),
+            #[cfg(feature = "searchstats")]
+            stats: search::SearchStats::default(),
             counter_moves: unsafe { std::mem::zeroed() },
             main_history: unsafe { std::mem::zeroed() },
             capture_history: unsafe { std::mem::zeroed() },
@@ -380,6 +390,13 @@ impl Position {
         self.st().key
     }
 
+    // previous_key() returns the key of the position before the last move,
+    // if that position is known
+    pub fn previous_key(&self) -> Option<Key> {
+        let len = self.states.len();
+        if len < 2 { None } else { Some(self.states[len - 2].key) }
+    }
+
     pub fn pawn_key(&self) -> Key {
         self.st().pawn_key
     }
@@ -438,6 +455,28 @@ impl Position {
         self.st().captured_piece
     }
 
+    // init_tables() (re)allocates the pawn and material hash tables, which
+    // are needed to evaluate the position. The number of entries of each
+    // table must be a power of 2.
+
+    pub fn init_tables(&mut self, pawn_entries: usize,
+        material_entries: usize
+    ) {
+        debug_assert!(pawn_entries.is_power_of_two());
+        debug_assert!(material_entries.is_power_of_two());
+
+        self.pawns_table = Vec::with_capacity(pawn_entries);
+        for _ in 0..pawn_entries {
+            self.pawns_table
+                .push(std::cell::UnsafeCell::new(pawns::Entry::new()));
+        }
+        self.material_table = Vec::with_capacity(material_entries);
+        for _ in 0..material_entries {
+            self.material_table
+                .push(std::cell::UnsafeCell::new(material::Entry::new()));
+        }
+    }
+
     pub const PIECE_TO_CHAR: &'static str = " PNBRQK  pnbrqk";
 
     pub fn print(&mut self) {
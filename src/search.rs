@@ -1,14 +1,19 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use bitboard::*;
+use cluster;
 use evaluate;
 use evaluate::evaluate;
+use json;
+use mate;
+use mcts;
 use movegen::*;
 use movepick::*;
 use position::*;
 use tb;
 use threads;
 use timeman;
+use treedump;
 use tt;
 use types::*;
 use uci;
@@ -17,6 +22,7 @@ use ucioption;
 use std;
 use std::io::stdout;
 use std::io::Write;
+use std::sync::atomic::Ordering;
 use std::time::Instant;
 
 pub const CM_THRESHOLD: i32 = 0;
@@ -86,10 +92,18 @@ impl PartialEq for RootMove {
 
 pub type RootMoves = Vec<RootMove>;
 
+// How a per-move delay is applied to the clock
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DelayType { Simple, Bronstein }
+
 #[derive(Clone)]
 pub struct LimitsType {
     pub time: [i64; 2],
     pub inc: [i64; 2],
+    pub delay: [i64; 2],
+    pub delay_type: DelayType,
+    pub byoyomi: [i64; 2],
+    pub period_moves: i32,
     pub movestogo: i32,
     pub depth: u32,
     pub movetime: i64,
@@ -97,6 +111,7 @@ pub struct LimitsType {
     pub perft: u32,
     pub infinite: bool,
     pub nodes: u64,
+    pub exact_nodes: bool,
     pub start_time: Option<Instant>,
 }
 
@@ -105,6 +120,10 @@ impl LimitsType {
         LimitsType {
             time: [0; 2],
             inc: [0; 2],
+            delay: [0; 2],
+            delay_type: DelayType::Simple,
+            byoyomi: [0; 2],
+            period_moves: 0,
             movestogo: 0,
             depth: 0,
             movetime: 0,
@@ -112,6 +131,7 @@ impl LimitsType {
             perft: 0,
             infinite: false,
             nodes: 0,
+            exact_nodes: false,
             start_time: Some(Instant::now()),
         }
     }
@@ -125,6 +145,10 @@ impl LimitsType {
 pub static mut LIMITS: LimitsType = LimitsType {
     time: [0; 2],
     inc: [0; 2],
+    delay: [0; 2],
+    delay_type: DelayType::Simple,
+    byoyomi: [0; 2],
+    period_moves: 0,
     movestogo: 0,
     depth: 0,
     movetime: 0,
@@ -132,6 +156,7 @@ pub static mut LIMITS: LimitsType = LimitsType {
     perft: 0,
     infinite: false,
     nodes: 0,
+    exact_nodes: false,
     start_time: None,
 };
 
@@ -141,6 +166,70 @@ pub fn limits() -> &'static mut LimitsType {
     }
 }
 
+// Counters of how often the search heuristics apply, printed by the "stats"
+// command. They are only compiled in with the "searchstats" cargo feature.
+#[cfg(feature = "searchstats")]
+#[derive(Clone, Copy, Default)]
+pub struct SearchStats {
+    pub pv_nodes: u64,
+    pub non_pv_nodes: u64,
+    pub qsearch_nodes: u64,
+    pub tt_cutoffs: u64,
+    pub null_move_tries: u64,
+    pub null_move_cutoffs: u64,
+    pub futility_prunes: u64,
+    pub lmr_searches: u64,
+    pub lmr_researches: u64,
+    pub probcuts: u64,
+    pub singular_extensions: u64,
+    pub beta_cutoffs: u64,
+    pub first_move_cutoffs: u64,
+    pub expanded_nodes: u64,
+    pub moves_searched: u64,
+    // Sum of the logarithms of nodes(d) / nodes(d - 1) over the iterations
+    // completed right after the one a ply shallower
+    pub log_branching: f64,
+    pub iterations: u64,
+}
+
+#[cfg(feature = "searchstats")]
+impl SearchStats {
+    pub fn add(&mut self, other: &SearchStats) {
+        self.pv_nodes += other.pv_nodes;
+        self.non_pv_nodes += other.non_pv_nodes;
+        self.qsearch_nodes += other.qsearch_nodes;
+        self.tt_cutoffs += other.tt_cutoffs;
+        self.null_move_tries += other.null_move_tries;
+        self.null_move_cutoffs += other.null_move_cutoffs;
+        self.futility_prunes += other.futility_prunes;
+        self.lmr_searches += other.lmr_searches;
+        self.lmr_researches += other.lmr_researches;
+        self.probcuts += other.probcuts;
+        self.singular_extensions += other.singular_extensions;
+        self.beta_cutoffs += other.beta_cutoffs;
+        self.first_move_cutoffs += other.first_move_cutoffs;
+        self.expanded_nodes += other.expanded_nodes;
+        self.moves_searched += other.moves_searched;
+        self.log_branching += other.log_branching;
+        self.iterations += other.iterations;
+    }
+}
+
+// stat!() increments a counter of SearchStats, or adds a bool or number to
+// it. Without the "searchstats" feature it expands to nothing and the
+// arguments are not evaluated.
+#[cfg(feature = "searchstats")]
+macro_rules! stat {
+    ($pos:expr, $field:ident) => { $pos.stats.$field += 1 };
+    ($pos:expr, $field:ident, $n:expr) => { $pos.stats.$field += $n as u64 };
+}
+
+#[cfg(not(feature = "searchstats"))]
+macro_rules! stat {
+    ($pos:expr, $field:ident) => {};
+    ($pos:expr, $field:ident, $n:expr) => {};
+}
+
 // Different node types
 #[derive(Clone, Copy, PartialEq, Eq)]
 struct NonPv;
@@ -169,6 +258,11 @@ fn futility_margin(d: Depth) -> Value {
     Value(150 * d / ONE_PLY)
 }
 
+// Minimum time in milliseconds before the main thread starts reporting
+// the current root move, and the interval between progress reports
+const CURRMOVE_TIME: i64 = 3000;
+const INFO_INTERVAL: i64 = 1000;
+
 const RAZOR_MARGIN1: i32 = 590;
 const RAZOR_MARGIN2: i32 = 604;
 
@@ -214,7 +308,13 @@ fn perft<Root: Bool>(pos: &mut Position, depth: Depth) -> u64 {
             pos.undo_move(m);
         }
         if Root::BOOL {
-            println!("{}: {}", uci::move_str(m, pos.is_chess960()), cnt);
+            let m = uci::move_str(m, pos.is_chess960());
+            if uci::json() {
+                println!("{}", json::Object::new("perft").str("move", &m)
+                    .num("nodes", cnt).end());
+            } else {
+                println!("{}: {}", m, cnt);
+            }
             stdout().flush().unwrap();
         }
     }
@@ -256,9 +356,10 @@ pub fn init() {
 
 pub fn clear() {
     threads::wait_for_all();
-    tt::clear();
-    threads::clear_search();
+    threads::clear_search(); // Also clears the TT in parallel
     threads::wait_for_all();
+    threads::clear_analysis();
+    threads::clear_ponder_results();
 }
 
 // mainthread_search() is called by the main thread when the program
@@ -268,23 +369,41 @@ pub fn clear() {
 pub fn mainthread_search(pos: &mut Position, th: &threads::ThreadCtrl) {
     if limits().perft != 0 {
         let nodes = perft::<True>(pos, (limits().perft as i32) * ONE_PLY);
-        println!("\nNodes searched: {}", nodes);
+        if uci::json() {
+            println!("{}", json::Object::new("perft").num("nodes", nodes)
+                .end());
+        } else {
+            println!("\nNodes searched: {}", nodes);
+        }
         return;
     }
 
     let us = pos.side_to_move();
     timeman::init(limits(), us, pos.game_ply());
     tt::new_search();
+    pos.last_info_time = 0;
 
    if pos.root_moves.is_empty() {
         pos.root_moves.push(RootMove::new(Move::NONE));
-        println!("info depth 0 score {}", uci::value(
-            if pos.checkers() != 0 { -Value::MATE } else { Value::DRAW }));
+        let v = if pos.checkers() != 0 { -Value::MATE } else { Value::DRAW };
+        if MoveList::new::<Legal>(pos).len() != 0 {
+            // All legal moves were left out by searchmoves or excludemoves
+            println!("info string No moves left to search");
+        } else if uci::json() {
+            println!("{}", json::Object::new("info").num("depth", 0)
+                .raw("score", &uci::value_json(v)).end());
+        } else {
+            println!("info depth 0 score {}", uci::value(v));
+        }
         stdout().flush().unwrap();
+    } else if limits().mate != 0 {
+        mate::search(pos); // The helper threads are not used
     } else {
         threads::wake_up_slaves();
 
+        treedump::start(pos);
         thread_search(pos, th); // Let's start searching!
+        treedump::finish();
     }
 
     // When we reach the maximum depth, we can arrive here without
@@ -295,8 +414,7 @@ pub fn mainthread_search(pos: &mut Position, th: &threads::ThreadCtrl) {
     // one of those commands (which also raised threads::stop()).
     threads::set_stop_on_ponderhit(true);
 
-    while !threads::stop() && (threads::ponder() || limits().infinite) {
-    } // Busy wait for a stop or a ponder reset
+    threads::wait_for_stop(limits().infinite);
 
     // Stop the threads if not already stopped (also raise the stop if
     // "ponderhit" has just reset threads::ponder()).
@@ -305,33 +423,91 @@ pub fn mainthread_search(pos: &mut Position, th: &threads::ThreadCtrl) {
     // Wait until all threads have finished
     threads::wait_for_slaves();
 
-    // Check if there are threads with a better score than main thread
-    if ucioption::get_i32("MultiPV") == 1
-        && limits().depth == 0
+    // Stop the other processes of a cluster and collect their results
+    let remote_results = cluster::finish_search();
+
+    // After "ponderhit" the ponder results are of no further use
+    if !threads::ponder() {
+        threads::clear_ponder_results();
+    }
+
+    timeman::search_done(limits(), us);
+    timeman::write_log(limits(), us, pos.game_ply());
+
+    // Choose the best move among all threads and cluster workers. The
+    // chosen line goes to the front, ahead of the other MultiPV lines.
+    threads::set_result(th, pos);
+    if !mcts::enabled()
         && pos.root_moves[0].pv[0] != Move::NONE
+        && pos.completed_depth > Depth::ZERO
     {
-        let common = th.common.lock().unwrap();
-        let result = &mut common.result.lock().unwrap();
-        if result.score > pos.root_moves[0].score
-            && (result.depth >= pos.completed_depth
-                || result.score >= Value::MATE_IN_MAX_PLY)
-        {
-            pos.root_moves[0].score = result.score;
-            pos.root_moves[0].pv = result.pv.clone();
+        let mut results = threads::results();
+        results.extend(remote_results.into_iter().filter(|r|
+            pos.root_moves.iter().any(|rm| rm.pv[0] == r.pv[0])));
+
+        let best = if ucioption::get_bool("Thread Voting") {
+            threads::pick_by_votes(&results)
+        } else {
+            threads::pick_by_score(&results)
+        };
+        if best != 0 {
+            let r = &results[best];
+            let idx = pos.root_moves.iter()
+                .position(|rm| rm.pv[0] == r.pv[0]).unwrap();
+            let mut rm = pos.root_moves.remove(idx);
+            rm.score = r.score;
+            rm.pv = r.pv.clone();
+            pos.root_moves.insert(0, rm);
         }
     }
 
+    cluster::search_done(pos.completed_depth, pos.root_moves[0].score,
+        &pos.root_moves[0].pv);
+
     pos.previous_score = pos.root_moves[0].score;
 
-    print!("bestmove {}", uci::move_str(pos.root_moves[0].pv[0],
-        pos.is_chess960()));
+    threads::set_best_move(pos.root_moves[0].pv[0]);
+
+    if (limits().infinite || ucioption::get_bool("UCI_AnalyseMode"))
+        && limits().mate == 0
+        && pos.root_moves[0].pv[0] != Move::NONE
+        && pos.completed_depth > Depth::ZERO
+    {
+        threads::save_analysis(pos, pos.completed_depth);
+    }
+
+    let multi_pv = std::cmp::min(ucioption::get_i32("MultiPV") as usize,
+        pos.root_moves.len());
+    threads::set_root_results(pos.root_moves[..multi_pv].iter()
+        .map(|rm| (rm.pv[0], if rm.score != -Value::INFINITE { rm.score }
+            else { rm.previous_score }))
+        .collect());
+
+    let best_move = uci::move_str(pos.root_moves[0].pv[0], pos.is_chess960());
+    let ponder_move =
+        if pos.root_moves[0].pv.len() > 1 || extract_ponder_from_tt(pos) {
+            Some(uci::move_str(pos.root_moves[0].pv[1], pos.is_chess960()))
+        } else {
+            None
+        };
 
-    if pos.root_moves[0].pv.len() > 1 || extract_ponder_from_tt(pos) {
-        print!(" ponder {}", uci::move_str(pos.root_moves[0].pv[1],
-            pos.is_chess960()));
+    if uci::quiet() {
+        return;
     }
 
-    print!("\n");
+    if uci::json() {
+        let mut obj = json::Object::new("bestmove").str("bestmove", &best_move);
+        if let Some(ref m) = ponder_move {
+            obj = obj.str("ponder", m);
+        }
+        println!("{}", obj.end());
+    } else {
+        print!("bestmove {}", best_move);
+        if let Some(ref m) = ponder_move {
+            print!(" ponder {}", m);
+        }
+        print!("\n");
+    }
     stdout().flush().unwrap();
 }
 
@@ -340,7 +516,7 @@ pub fn mainthread_search(pos: &mut Position, th: &threads::ThreadCtrl) {
 // been consumed, the user stops the search, or the maximum search depth
 // is reached.
 
-pub fn thread_search(pos: &mut Position, _th: &threads::ThreadCtrl) {
+pub fn thread_search(pos: &mut Position, th: &threads::ThreadCtrl) {
     let mut stack: Vec<Stack> = Vec::with_capacity((MAX_PLY + 7) as usize);
 
     let mut last_best_move = Move::NONE;
@@ -399,7 +575,24 @@ pub fn thread_search(pos: &mut Position, _th: &threads::ThreadCtrl) {
         evaluate::CONTEMPT = if us == WHITE { contempt } else { -contempt };
     }
 
-    let mut root_depth = Depth::ZERO;
+    // A resumed analysis repeats the last depth of the old search, which
+    // is quick with the TT and gives the GUI a PV at once.
+    let mut root_depth = std::cmp::max(
+        th.common.lock().unwrap().start_depth - ONE_PLY, Depth::ZERO);
+    if limits().depth != 0 {
+        root_depth = std::cmp::min(root_depth,
+            (limits().depth as i32 - 1) * ONE_PLY);
+    }
+    pos.completed_depth = root_depth;
+
+    if mcts::enabled() {
+        mcts::search(pos, &mut stack);
+        return;
+    }
+
+    // Depth and nodes of the last completed iteration
+    #[cfg(feature = "searchstats")]
+    let mut last_iteration = (Depth::ZERO, 0u64);
 
     // Iterative deepening loop until requested to stop or the target depth
     // is reached
@@ -423,6 +616,9 @@ pub fn thread_search(pos: &mut Position, _th: &threads::ThreadCtrl) {
             }
         }
 
+        #[cfg(feature = "searchstats")]
+        let iteration_start = pos.nodes;
+
         // Age out PV variability metric
         if pos.is_main {
             pos.best_move_changes *= 0.517;
@@ -562,6 +758,19 @@ pub fn thread_search(pos: &mut Position, _th: &threads::ThreadCtrl) {
 
         if !threads::stop() {
             pos.completed_depth = root_depth;
+
+            #[cfg(feature = "searchstats")]
+            {
+                let nodes = pos.nodes - iteration_start;
+                if last_iteration.0 == root_depth - ONE_PLY
+                    && last_iteration.1 != 0 && nodes != 0
+                {
+                    pos.stats.log_branching +=
+                        (nodes as f64 / last_iteration.1 as f64).ln();
+                    pos.stats.iterations += 1;
+                }
+                last_iteration = (root_depth, nodes);
+            }
         }
 
         if pos.root_moves[0].pv[0] != last_best_move {
@@ -598,18 +807,23 @@ pub fn thread_search(pos: &mut Position, _th: &threads::ThreadCtrl) {
                 // the more. Use part of the gained time from a previous
                 // stable move for the current move.
                 time_reduction = 1.;
+                let mut stop_factor = f64::MAX;
                 for i in 3..6 {
                     if last_best_move_depth * i < pos.completed_depth {
                         time_reduction *= 1.25;
                     }
                     unstable_pv_factor *=
                         pos.previous_time_reduction.powf(0.528) / time_reduction;
+                    stop_factor = stop_factor.min(
+                        unstable_pv_factor * (improving_factor as f64) / 581.0);
 
                     if pos.root_moves.len() == 1
-                        || (timeman::elapsed() as f64) >
+                        || ((timeman::elapsed() as f64) >
                             (timeman::optimum() as f64) *
                             unstable_pv_factor *
                             (improving_factor as f64) / 581.0
+                            && timeman::elapsed() - timeman::pondered()
+                                >= timeman::minimum())
                     {
                         // If we are allowed to ponder do not stop the search
                         // now but keep pondering until the GUI sends
@@ -621,11 +835,27 @@ pub fn thread_search(pos: &mut Position, _th: &threads::ThreadCtrl) {
                         }
                     }
                 }
+
+                if timeman::log_enabled() {
+                    if pos.root_moves.len() == 1 {
+                        stop_factor = 0.;
+                    }
+                    timeman::log_iteration(root_depth, pos.best_move_changes,
+                        pos.failed_low, improving_factor, time_reduction,
+                        unstable_pv_factor, stop_factor);
+                }
             }
         }
 
     }
 
+    // Keep the root results in case the search was pondering on a move that
+    // the opponent does not play
+    if threads::ponder() {
+        threads::save_ponder_results(pos, pos.completed_depth,
+            &pos.root_moves);
+    }
+
     if !pos.is_main {
         return;
     }
@@ -633,9 +863,33 @@ pub fn thread_search(pos: &mut Position, _th: &threads::ThreadCtrl) {
     pos.previous_time_reduction = time_reduction;
 }
 
-// search() is the main search function for both PV and non-PV nodes
+// search() is the main search function for both PV and non-PV nodes. With
+// the "treedump" feature it records each node around search_node(), see
+// treedump.rs.
+
+#[cfg(feature = "treedump")]
+fn search<NT: NodeType>(
+    pos: &mut Position, ss: &mut [Stack], alpha: Value, beta: Value,
+    depth: Depth, cut_node: bool, skip_early_pruning: bool
+) -> Value {
+    treedump::enter(pos, ss[5].ply, ss[4].current_move, alpha, beta, depth);
+    let value = search_node::<NT>(pos, ss, alpha, beta, depth, cut_node,
+        skip_early_pruning);
+    treedump::exit(pos, ss[5].ply, value);
+    value
+}
 
+#[cfg(not(feature = "treedump"))]
+#[inline(always)]
 fn search<NT: NodeType>(
+    pos: &mut Position, ss: &mut [Stack], alpha: Value, beta: Value,
+    depth: Depth, cut_node: bool, skip_early_pruning: bool
+) -> Value {
+    search_node::<NT>(pos, ss, alpha, beta, depth, cut_node,
+        skip_early_pruning)
+}
+
+fn search_node<NT: NodeType>(
     pos: &mut Position, ss: &mut [Stack], mut alpha: Value, mut beta: Value,
     depth: Depth, cut_node: bool, skip_early_pruning: bool
 ) -> Value {
@@ -661,12 +915,15 @@ fn search<NT: NodeType>(
     let mut best_value = -Value::INFINITE;
     let mut max_value = Value::INFINITE;
 
+    stat!(pos, pv_nodes, pv_node);
+    stat!(pos, non_pv_nodes, !pv_node);
+
     // Check for the available remaining time
     pos.calls_cnt -= 1;
     if pos.calls_cnt < 0 {
-        pos.calls_cnt = 4095;
+        pos.calls_cnt = calls_between_checks();
         update_counters(pos);
-        check_time();
+        check_time(pos);
     }
 
     // Used to send sel_depth info to GUI
@@ -747,6 +1004,7 @@ fn search<NT: NodeType>(
                     pos.moved_piece(tt_move), tt_move.to(), penalty);
             }
         }
+        stat!(pos, tt_cutoffs);
         return tt_value;
     }
 
@@ -849,6 +1107,8 @@ fn search<NT: NodeType>(
                 Move::NONE, eval, tt::generation());
         }
 
+        treedump::eval(pos, ss[5].ply, ss[5].static_eval);
+
         if skip_early_pruning
             || pos.non_pawn_material_c(pos.side_to_move()) == Value::ZERO
         {
@@ -860,6 +1120,7 @@ fn search<NT: NodeType>(
             && depth <= ONE_PLY
         {
             if eval + RAZOR_MARGIN1 <= alpha {
+                treedump::note(pos, ss[5].ply, "razoring", Move::NONE, None);
                 return qsearch::<NonPv, False>(pos, ss, alpha, alpha+1,
                         Depth::ZERO);
             }
@@ -872,6 +1133,7 @@ fn search<NT: NodeType>(
             let v = qsearch::<NonPv, False>(pos, ss, ralpha, ralpha+1,
                 Depth::ZERO);
             if v <= ralpha {
+                treedump::note(pos, ss[5].ply, "razoring", Move::NONE, None);
                 return v;
             }
         }
@@ -882,6 +1144,8 @@ fn search<NT: NodeType>(
             && eval - futility_margin(depth) >= beta
             && eval < Value::KNOWN_WIN
         {
+            treedump::note(pos, ss[5].ply, "futility", Move::NONE, None);
+            stat!(pos, futility_prunes);
             return eval;
         }
 
@@ -901,6 +1165,9 @@ fn search<NT: NodeType>(
             ss[5].current_move = Move::NULL;
             ss[5].cont_history = pos.cont_history.get(NO_PIECE, Square(0));
 
+            treedump::note(pos, ss[5].ply, "null move", Move::NONE,
+                Some(r / ONE_PLY));
+            stat!(pos, null_move_tries);
             pos.do_null_move();
             let mut null_value = if depth-r < ONE_PLY {
                 -qsearch::<NonPv, False>(pos, &mut ss[1..], -beta, -beta+1,
@@ -920,6 +1187,9 @@ fn search<NT: NodeType>(
                 if (depth < 12 * ONE_PLY || pos.nmp_ply != 0)
                     && beta.abs() < Value::KNOWN_WIN
                 {
+                    treedump::note(pos, ss[5].ply, "null move cutoff",
+                        Move::NONE, None);
+                    stat!(pos, null_move_cutoffs);
                     return null_value;
                 }
 
@@ -936,6 +1206,9 @@ fn search<NT: NodeType>(
                 pos.nmp_odd = 0;
                 pos.nmp_ply = 0;
                 if v >= beta {
+                    treedump::note(pos, ss[5].ply, "null move cutoff",
+                        Move::NONE, None);
+                    stat!(pos, null_move_cutoffs);
                     return null_value;
                 }
             }
@@ -985,6 +1258,8 @@ fn search<NT: NodeType>(
 
                     pos.undo_move(m);
                     if value >= rbeta {
+                        treedump::note(pos, ss[5].ply, "probcut", m, None);
+                        stat!(pos, probcuts);
                         return value;
                     }
                     prob_cut_count -= 1;
@@ -1001,6 +1276,8 @@ fn search<NT: NodeType>(
             && (pv_node || ss[5].static_eval + 256 >= beta)
         {
             let d = (3 * depth / (4 * ONE_PLY) - 2) * ONE_PLY;
+            treedump::note(pos, ss[5].ply, "iid", Move::NONE,
+                Some(d / ONE_PLY));
             search::<NT>(pos, ss, alpha, beta, d, cut_node, true);
 
             let (tmp_tte, tmp_tt_hit) = tt::probe(pos_key);
@@ -1035,6 +1312,8 @@ fn search<NT: NodeType>(
     let mut tt_capture = false;
     let pv_exact = pv_node && tt_hit && tte.bound() == Bound::EXACT;
 
+    stat!(pos, expanded_nodes);
+
     // Step 12. Loop through moves
     // Loop through all pseudo-legal moves until no moves remain or a beta
     // cutoff occurs
@@ -1063,11 +1342,19 @@ fn search<NT: NodeType>(
         move_count += 1;
         ss[5].move_count = move_count;
 
-        if root_node && pos.is_main && timeman::elapsed() > 3000 {
-            println!( "info depth {} currmove {} currmovenumber {}",
-                depth / ONE_PLY, uci::move_str(m, pos.is_chess960()),
-                move_count + pos.pv_idx as i32
-            );
+        if root_node && pos.is_main && timeman::elapsed() > CURRMOVE_TIME
+            && !uci::quiet()
+        {
+            let curr_move = uci::move_str(m, pos.is_chess960());
+            let curr_move_number = move_count + pos.pv_idx as i32;
+            if uci::json() {
+                println!("{}", json::Object::new("info")
+                    .num("depth", depth / ONE_PLY).str("currmove", &curr_move)
+                    .num("currmovenumber", curr_move_number).end());
+            } else {
+                println!("info depth {} currmove {} currmovenumber {}",
+                    depth / ONE_PLY, curr_move, curr_move_number);
+            }
             stdout().flush().unwrap();
         }
 
@@ -1112,6 +1399,8 @@ fn search<NT: NodeType>(
             ss[5].excluded_move = Move::NONE;
 
             if value < rbeta {
+                treedump::note(pos, ss[5].ply, "singular extension", m, None);
+                stat!(pos, singular_extensions);
                 extension = ONE_PLY;
             }
         } else if gives_check
@@ -1136,6 +1425,8 @@ fn search<NT: NodeType>(
             {
                 // Move count based pruning
                 if move_count_pruning {
+                    treedump::note(pos, ss[5].ply, "move count pruning", m,
+                        None);
                     skip_quiets = true;
                     continue;
                 }
@@ -1150,6 +1441,8 @@ fn search<NT: NodeType>(
                     && cont_hist.0.get(moved_piece, m.to()) < CM_THRESHOLD
                     && cont_hist.1.get(moved_piece, m.to()) < CM_THRESHOLD
                 {
+                    treedump::note(pos, ss[5].ply, "countermove pruning", m,
+                        None);
                     continue;
                 }
 
@@ -1158,6 +1451,9 @@ fn search<NT: NodeType>(
                     && !in_check
                     && ss[5].static_eval + 256 + 200 * lmr_depth <= alpha
                 {
+                    treedump::note(pos, ss[5].ply, "futility pruning", m,
+                        None);
+                    stat!(pos, futility_prunes);
                     continue;
                 }
 
@@ -1165,12 +1461,14 @@ fn search<NT: NodeType>(
                 if lmr_depth < 8
                     && !pos.see_ge(m, Value(-35 * lmr_depth * lmr_depth))
                 {
+                    treedump::note(pos, ss[5].ply, "see pruning", m, None);
                     continue;
                 }
             } else if depth < 7 * ONE_PLY
                 && extension == Depth::ZERO
                 && !pos.see_ge(m, -PawnValueEg * (depth / ONE_PLY))
             {
+                treedump::note(pos, ss[5].ply, "see pruning", m, None);
                 continue;
             }
         }
@@ -1195,6 +1493,7 @@ fn search<NT: NodeType>(
 
         // Step 15. Make the move
         pos.do_move(m, gives_check);
+        stat!(pos, moves_searched);
 
         // Step 16. Reduced depth search (LMR). If the move fails high it will
         // be re-searched at full depth.
@@ -1262,9 +1561,15 @@ fn search<NT: NodeType>(
 
             let d = std::cmp::max(new_depth - r, ONE_PLY);
 
+            if d != new_depth {
+                treedump::note(pos, ss[5].ply, "lmr", m,
+                    Some((new_depth - d) / ONE_PLY));
+                stat!(pos, lmr_searches);
+            }
             value = -search::<NonPv>(pos, &mut ss[1..], -(alpha+1), -alpha, d,
                 true, false);
             do_full_depth_search = value > alpha && d != new_depth;
+            stat!(pos, lmr_researches, do_full_depth_search);
         } else {
             do_full_depth_search = !pv_node || move_count > 1;
         }
@@ -1365,6 +1670,8 @@ fn search<NT: NodeType>(
                     alpha = value;
                 } else {
                     debug_assert!(value >= beta); // Fail high
+                    stat!(pos, beta_cutoffs);
+                    stat!(pos, first_move_cutoffs, move_count == 1);
                     break;
                 }
             }
@@ -1419,11 +1726,13 @@ fn search<NT: NodeType>(
     }
 
     if excluded_move == Move::NONE {
-        tte.save(pos_key, value_to_tt(best_value, ss[5].ply),
-            if best_value >= beta { Bound::LOWER }
+        let b = if best_value >= beta { Bound::LOWER }
             else if pv_node && best_move != Move::NONE { Bound::EXACT }
-            else { Bound::UPPER },
-            depth, best_move, ss[5].static_eval, tt::generation());
+            else { Bound::UPPER };
+        let v = value_to_tt(best_value, ss[5].ply);
+        tte.save(pos_key, v, b, depth, best_move, ss[5].static_eval,
+            tt::generation());
+        cluster::share(pos_key, v, b, depth, best_move, ss[5].static_eval);
     }
 
     debug_assert!(best_value > -Value::INFINITE
@@ -1432,6 +1741,24 @@ fn search<NT: NodeType>(
     return best_value;
 }
 
+// leaf_value() evaluates a leaf of the MCTS tree, reached from the root by
+// ply moves the last of which was last, with a full-window quiescence
+// search. The value is from the point of view of the side to move.
+
+pub fn leaf_value(
+    pos: &mut Position, ss: &mut [Stack], ply: i32, last: Move
+) -> Value {
+    ss[4].current_move = last;
+    ss[5].ply = ply;
+    if pos.checkers() != 0 {
+        qsearch::<Pv, True>(pos, ss, -Value::INFINITE, Value::INFINITE,
+            Depth::ZERO)
+    } else {
+        qsearch::<Pv, False>(pos, ss, -Value::INFINITE, Value::INFINITE,
+            Depth::ZERO)
+    }
+}
+
 // qsearch() is the quiescence search function, which is called by the main
 // search function with depth zero or recursively with depth less than ONE_PLY.
 
@@ -1459,6 +1786,8 @@ fn qsearch<NT: NodeType, InCheck: Bool> (
     ss[6].ply = ss[5].ply + 1;
     let mut move_count = 0;
 
+    stat!(pos, qsearch_nodes);
+
     // Check for an instant draw or if maximum ply count has been reached
     if pos.is_draw(ss[5].ply) || ss[5].ply >= MAX_PLY {
         return if ss[5].ply >= MAX_PLY && !in_check { evaluate(pos) }
@@ -1486,6 +1815,7 @@ fn qsearch<NT: NodeType, InCheck: Bool> (
         && (if tt_value >= beta { tte.bound() & Bound::LOWER != 0 }
             else { tte.bound() & Bound::UPPER != 0 })
     {
+        stat!(pos, tt_cutoffs);
         return tt_value;
     }
 
@@ -1761,36 +2091,91 @@ fn update_stats(
     }
 }
 
-fn update_counters(pos: &Position) {
+// calls_between_checks() returns how many nodes a thread searches before it
+// publishes its node count and checks the limits again. Without a node
+// limit this is every 4096 nodes. With one it is every 1/1024 of the limit,
+// so that each thread overshoots it by at most about 0.1%, and when node
+// limits must be counted exactly (Deterministic) it is every node.
+
+fn calls_between_checks() -> i32 {
+    let nodes = limits().nodes;
+    if nodes == 0 {
+        4095
+    } else if limits().exact_nodes {
+        0
+    } else {
+        std::cmp::min(nodes / 1024, 4095) as i32
+    }
+}
+
+pub fn update_counters(pos: &Position) {
     let th = pos.thread_ctrl.as_ref().unwrap();
-    th.nodes.set(pos.nodes);
-    th.tb_hits.set(pos.tb_hits);
+    th.nodes.store(pos.nodes, Ordering::Relaxed);
+    th.tb_hits.store(pos.tb_hits, Ordering::Relaxed);
 }
 
 // check_time() is used to print debug info and, more importantly, to detect
 // when we are out of available time and have to stop the search.
 
-fn check_time() {
+pub fn check_time(pos: &mut Position) {
+    let elapsed = timeman::elapsed();
+
+    // Only the main thread sends periodic progress reports to the GUI
+    if pos.is_main && elapsed - pos.last_info_time >= INFO_INTERVAL {
+        pos.last_info_time = elapsed;
+        print_progress(elapsed);
+    }
+
     // An engine may not stop pondering until told so by the GUI
     if threads::ponder() {
         return;
     }
 
-    let elapsed = timeman::elapsed();
+    // Time spent pondering does not count against the clock
+    let used = elapsed - timeman::pondered();
 
-    if (limits().use_time_management() && elapsed > timeman::maximum() - 10)
-        || (limits().movetime != 0 && elapsed >= limits().movetime)
+    if (limits().use_time_management() && used > timeman::maximum() - 10)
+        || (limits().movetime != 0 && used >= limits().movetime)
         || (limits().nodes != 0 && threads::nodes_searched() >= limits().nodes)
     {
         threads::set_stop(true);
     }
 }
 
+// print_progress() sends a "heartbeat" info line with the node count, speed
+// and table statistics, so that the GUI is kept up to date during long
+// iterations when no PV is being printed.
+
+fn print_progress(elapsed: i64) {
+    if uci::quiet() {
+        return;
+    }
+
+    let elapsed = elapsed + 1;
+    let nodes_searched = threads::nodes_searched();
+
+    let nps = nodes_searched * 1000 / (elapsed as u64);
+
+    if uci::json() {
+        println!("{}", json::Object::new("info").num("nodes", nodes_searched)
+            .num("nps", nps).num("hashfull", tt::hashfull())
+            .num("tbhits", threads::tb_hits()).num("time", elapsed).end());
+    } else {
+        println!("info nodes {} nps {} hashfull {} tbhits {} time {}",
+            nodes_searched, nps, tt::hashfull(), threads::tb_hits(), elapsed);
+    }
+    stdout().flush().unwrap();
+}
+
 // print_pv() prints PV information according to the UCI protocol. UCI
 // requires that all (if any) unsearched PV lines are sent using a previous
 // search score.
 
-fn print_pv(pos: &mut Position, depth: Depth, alpha: Value, beta: Value) {
+pub fn print_pv(pos: &mut Position, depth: Depth, alpha: Value, beta: Value) {
+    if uci::quiet() {
+        return;
+    }
+
     let elapsed = timeman::elapsed() + 1;
     let pv_idx = pos.pv_idx;
     let multi_pv = std::cmp::min(ucioption::get_i32("MultiPV") as usize,
@@ -1825,19 +2210,44 @@ fn print_pv(pos: &mut Position, depth: Depth, alpha: Value, beta: Value) {
             tb::expand_mate(pos, i);
         }
 
+        let bound =
+            if tb || i != pv_idx { Bound::NONE }
+            else if v >= beta { Bound::LOWER }
+            else if v <= alpha { Bound::UPPER }
+            else { Bound::NONE };
+        let nps = nodes_searched * 1000 / (elapsed as u64);
+        let pv: Vec<String> = pos.root_moves[i].pv.iter()
+            .map(|&m| uci::move_str(m, pos.is_chess960())).collect();
+
+        if uci::json() {
+            let mut obj = json::Object::new("info").num("depth", d / ONE_PLY)
+                .num("seldepth", pos.root_moves[i].sel_depth + 1)
+                .num("multipv", i + 1).raw("score", &uci::value_json(v));
+            if bound == Bound::LOWER {
+                obj = obj.str("bound", "lower");
+            } else if bound == Bound::UPPER {
+                obj = obj.str("bound", "upper");
+            }
+            obj = obj.nums("wdl", &uci::wdl(v, pos.game_ply()))
+                .num("nodes", nodes_searched).num("nps", nps);
+            if elapsed > 1000 {
+                obj = obj.num("hashfull", tt::hashfull());
+            }
+            println!("{}", obj.num("tbhits", tb_hits).num("time", elapsed)
+                .strs("pv", &pv).end());
+            continue;
+        }
+
         print!("info depth {} seldepth {} multipv {} score {} ",
             d / ONE_PLY, pos.root_moves[i].sel_depth + 1, i + 1, uci::value(v));
 
-        if !tb && i == pv_idx {
-            if v >= beta {
-                print!("lowerbound ");
-            } else if v <= alpha {
-                print!("upperbound ");
-            }
+        if bound == Bound::LOWER {
+            print!("lowerbound ");
+        } else if bound == Bound::UPPER {
+            print!("upperbound ");
         }
 
-        print!("nodes {} nps {}",
-            nodes_searched, nodes_searched * 1000 / (elapsed as u64));
+        print!("nodes {} nps {}", nodes_searched, nps);
 
         if elapsed > 1000 {
             print!(" hashfull {}", tt::hashfull());
@@ -1845,8 +2255,8 @@ fn print_pv(pos: &mut Position, depth: Depth, alpha: Value, beta: Value) {
 
         print!(" tbhits {} time {} pv", tb_hits, elapsed);
 
-        for &m in pos.root_moves[i].pv.iter() {
-            print!(" {}", uci::move_str(m, pos.is_chess960()));
+        for m in pv.iter() {
+            print!(" {}", m);
         }
         println!("");
     }
@@ -1863,7 +2273,7 @@ fn extract_ponder_from_tt(pos: &mut Position) -> bool {
 
     let m1 = pos.root_moves[0].pv[0];
 
-    if m1 == Move::NONE {
+    if m1 == Move::NONE || m1 == Move::NULL {
         return false;
     }
 
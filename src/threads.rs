@@ -1,16 +1,17 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use material;
+use cluster;
+use mcts;
 use movegen::*;
-use pawns;
+use numa;
 use position::Position;
 use search::*;
 use tb;
+use tt;
 use types::*;
 use ucioption;
 
 use std;
-use std::cell::Cell;
 use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::sync::atomic::*;
 use std::sync::mpsc::*;
@@ -27,16 +28,45 @@ pub struct SearchResult {
     pub pv: Vec<Move>,
 }
 
+impl SearchResult {
+    pub fn none() -> SearchResult {
+        SearchResult {
+            depth: Depth::ZERO,
+            score: -Value::INFINITE,
+            pv: Vec::new(),
+        }
+    }
+}
+
+// Probe and hit counts of the pawn and material hash tables
+#[derive(Clone, Copy, Default)]
+pub struct TableStats {
+    pub pawns_probes: u64,
+    pub pawns_hits: u64,
+    pub material_probes: u64,
+    pub material_hits: u64,
+}
+
+impl TableStats {
+    fn add(&mut self, other: &TableStats) {
+        self.pawns_probes += other.pawns_probes;
+        self.pawns_hits += other.pawns_hits;
+        self.material_probes += other.material_probes;
+        self.material_hits += other.material_hits;
+    }
+}
+
 pub struct ThreadState {
     pub exit: bool,
     pub searching: bool,
     pub clear: bool,
+    pub clear_tt: bool, // Clear only the TT, see clear_tt()
 }
 
 pub struct CommonState {
     pub root_moves: Arc<RootMoves>,
     pub pos_data: Arc<RwLock<PosData>>,
-    pub result: Arc<Mutex<SearchResult>>,
+    pub start_depth: Depth, // Depth reached before, see resume_analysis()
 }
 
 pub struct ThreadCtrl {
@@ -44,9 +74,12 @@ pub struct ThreadCtrl {
     pub state: Mutex<ThreadState>,
     pub common: Mutex<CommonState>,
     pub cv: Condvar,
-    // nodes and tb_hits are Cell<u64> for lack of atomic u64 types
-    pub nodes: Cell<u64>,
-    pub tb_hits: Cell<u64>,
+    pub nodes: AtomicU64,
+    pub tb_hits: AtomicU64,
+    pub table_stats: Mutex<TableStats>,
+    pub result: Mutex<SearchResult>, // Best line of the last search
+    #[cfg(feature = "searchstats")]
+    pub search_stats: Mutex<SearchStats>,
 }
 
 impl ThreadCtrl {
@@ -57,6 +90,7 @@ impl ThreadCtrl {
                 exit: false,
                 searching: true,
                 clear: false,
+                clear_tt: false,
             }),
             common: Mutex::new(CommonState {
                 root_moves: Arc::new(Vec::new()),
@@ -64,32 +98,48 @@ impl ThreadCtrl {
                     fen: String::new(),
                     moves: Vec::new()
                 })),
-                result: Arc::new(Mutex::new(SearchResult {
-                    depth: Depth::ZERO,
-                    score: -Value::INFINITE,
-                    pv: Vec::new(),
-                })),
+                start_depth: Depth::ZERO,
             }),
             cv: Condvar::new(),
-            nodes: Cell::new(0),
-            tb_hits: Cell::new(0),
+            nodes: AtomicU64::new(0),
+            tb_hits: AtomicU64::new(0),
+            table_stats: Mutex::new(TableStats::default()),
+            result: Mutex::new(SearchResult::none()),
+            #[cfg(feature = "searchstats")]
+            search_stats: Mutex::new(SearchStats::default()),
         };
         thread_ctrl
     }
 }
 
-// Remove next line when nodes and tb_hits can be made atomic u64
-unsafe impl Sync for ThreadCtrl { }
-
 type Handlers = Vec<thread::JoinHandle<()>>;
 type Threads = Vec<Arc<ThreadCtrl>>;
 
-static mut HANDLERS: *mut Handlers = 0 as *mut Handlers;
-static mut THREADS: *mut Threads = 0 as *mut Threads;
+// The registry of search threads. HANDLERS is only used by the UI thread to
+// spawn and join threads, THREADS is read by all threads.
+static HANDLERS: Mutex<Handlers> = Mutex::new(Vec::new());
+static THREADS: RwLock<Threads> = RwLock::new(Vec::new());
+
+// threads() returns a snapshot of the registered threads, so that no lock is
+// held while waiting for them.
+
+fn threads() -> Threads {
+    THREADS.read().unwrap().clone()
+}
 
 static STOP: AtomicBool = AtomicBool::new(false);
 static PONDER: AtomicBool = AtomicBool::new(false);
 static STOP_ON_PONDERHIT: AtomicBool = AtomicBool::new(false);
+static BEST_MOVE: AtomicU32 = AtomicU32::new(0);
+
+// While MAIN_ONLY is set the main thread searches without waking the helper
+// threads, which keep their state for later searches
+static MAIN_ONLY: AtomicBool = AtomicBool::new(false);
+
+// Changes to STOP and PONDER are signalled through SIGNAL_CV, so that the
+// main thread can sleep while it waits for "stop" or "ponderhit".
+static SIGNAL_LOCK: Mutex<()> = Mutex::new(());
+static SIGNAL_CV: Condvar = Condvar::new();
 
 pub fn stop() -> bool {
     STOP.load(Ordering::Relaxed)
@@ -103,74 +153,315 @@ pub fn stop_on_ponderhit() -> bool {
     STOP_ON_PONDERHIT.load(Ordering::Relaxed)
 }
 
+// best_move() returns the best move reported by the last completed search
+
+pub fn best_move() -> Move {
+    Move(BEST_MOVE.load(Ordering::Relaxed))
+}
+
+pub fn set_best_move(m: Move) {
+    BEST_MOVE.store(m.0, Ordering::SeqCst);
+}
+
+// The best root moves of the last completed search with their scores, one
+// for each MultiPV line
+static ROOT_RESULTS: Mutex<Vec<(Move, Value)>> = Mutex::new(Vec::new());
+
+pub fn root_results() -> Vec<(Move, Value)> {
+    ROOT_RESULTS.lock().unwrap().clone()
+}
+
+pub fn set_root_results(results: Vec<(Move, Value)>) {
+    *ROOT_RESULTS.lock().unwrap() = results;
+}
+
+// Root results of a ponder search that was not ended by "ponderhit",
+// collected from all threads, with the keys of the pondered position and of
+// the position before the opponent's move. After a ponder miss, the next
+// search of the pondered position or of another reply to the same position
+// starts with its root moves ordered by these results instead of throwing
+// the pondering work away.
+struct PonderResults {
+    key: Option<Key>,
+    previous_key: Option<Key>,
+    moves: Vec<(Move, Depth, Value)>,
+}
+
+static PONDER_RESULTS: Mutex<PonderResults> = Mutex::new(PonderResults {
+    key: None,
+    previous_key: None,
+    moves: Vec::new(),
+});
+
+// save_ponder_results() adds the root moves of a thread that has finished
+// pondering. For each move the score of the deepest search is kept.
+
+pub fn save_ponder_results(
+    pos: &Position, depth: Depth, root_moves: &RootMoves
+) {
+    let mut results = PONDER_RESULTS.lock().unwrap();
+    if results.key != Some(pos.key()) {
+        results.key = Some(pos.key());
+        results.previous_key = pos.previous_key();
+        results.moves.clear();
+    }
+
+    for rm in root_moves.iter() {
+        // Moves not searched in the last, interrupted iteration keep the
+        // score of the previous iteration
+        let score = if rm.score != -Value::INFINITE { rm.score }
+            else { rm.previous_score };
+        if score == -Value::INFINITE {
+            continue;
+        }
+        match results.moves.iter_mut().find(|r| r.0 == rm.pv[0]) {
+            Some(r) => if depth > r.1 {
+                *r = (rm.pv[0], depth, score);
+            },
+            None => results.moves.push((rm.pv[0], depth, score)),
+        }
+    }
+}
+
+pub fn clear_ponder_results() {
+    let mut results = PONDER_RESULTS.lock().unwrap();
+    results.key = None;
+    results.previous_key = None;
+    results.moves.clear();
+}
+
+// seed_root_moves() orders the root moves by the saved ponder results if
+// they are for the same position or for another reply to the position the
+// pondered one arose from. Moves without a result keep their order after
+// the others. The results are used only once.
+
+fn seed_root_moves(pos: &Position, root_moves: &mut RootMoves) {
+    {
+        let results = PONDER_RESULTS.lock().unwrap();
+        let same = results.key == Some(pos.key())
+            || (results.previous_key.is_some()
+                && results.previous_key == pos.previous_key());
+        if same && !results.moves.is_empty() {
+            let score = |m: Move| results.moves.iter().find(|r| r.0 == m)
+                .map_or(-Value::INFINITE, |r| r.2);
+            root_moves.sort_by(|a, b| score(b.pv[0]).cmp(&score(a.pv[0])));
+        }
+    }
+    clear_ponder_results();
+}
+
+// The analysis session holds the result of the last analysis search, i.e.
+// one with "go infinite" or UCI_AnalyseMode. When the next analysis search
+// is of the same position or of a position along its PV, as when stepping
+// through a game in a GUI, it continues where the old search left off: the
+// root moves are seeded with the scores and PV lines of the session and
+// iterative deepening starts at the depth left of the old search. The
+// histories and the TT are kept between searches anyway.
+struct AnalysisSession {
+    keys: Vec<Key>, // Keys of the positions along the PV, the root first
+    pv: Vec<Move>,
+    depth: Depth,
+    root_moves: Vec<(Move, Value, Vec<Move>)>,
+}
+
+static ANALYSIS: Mutex<Option<AnalysisSession>> = Mutex::new(None);
+
+// save_analysis() starts a new analysis session from the root moves of the
+// position, which must be the root position of a finished search.
+
+pub fn save_analysis(pos: &mut Position, depth: Depth) {
+    let pv = pos.root_moves[0].pv.clone();
+
+    let mut keys = vec![pos.key()];
+    for &m in pv.iter() {
+        let gives_check = pos.gives_check(m);
+        pos.do_move(m, gives_check);
+        keys.push(pos.key());
+    }
+    for &m in pv.iter().rev() {
+        pos.undo_move(m);
+    }
+
+    let root_moves = pos.root_moves.iter()
+        .map(|rm| (rm.pv[0], if rm.score != -Value::INFINITE { rm.score }
+            else { rm.previous_score }, rm.pv.clone()))
+        .collect();
+
+    *ANALYSIS.lock().unwrap() = Some(AnalysisSession {
+        keys,
+        pv,
+        depth,
+        root_moves,
+    });
+}
+
+pub fn clear_analysis() {
+    *ANALYSIS.lock().unwrap() = None;
+}
+
+// resume_analysis() seeds the root moves from the analysis session if the
+// position is on its PV, 'ply' plies from the session's root, and returns
+// the depth that is left of the session's search from there.
+
+fn resume_analysis(pos: &Position, root_moves: &mut RootMoves) -> Depth {
+    let session = ANALYSIS.lock().unwrap();
+    let s = match *session {
+        Some(ref s) => s,
+        None => return Depth::ZERO,
+    };
+    let ply = match s.keys.iter().position(|&k| k == pos.key()) {
+        Some(ply) => ply,
+        None => return Depth::ZERO,
+    };
+
+    if ply == 0 {
+        for rm in root_moves.iter_mut() {
+            if let Some(r) = s.root_moves.iter().find(|r| r.0 == rm.pv[0]) {
+                rm.score = r.1;
+                rm.pv = r.2.clone();
+            }
+        }
+    } else if ply < s.pv.len() {
+        // The score of the session's root, seen from the side to move and
+        // with mate distances counted from here
+        let mut v = s.root_moves[0].1;
+        if v >= Value::MATE_IN_MAX_PLY {
+            v += ply as i32;
+        } else if v <= Value::MATED_IN_MAX_PLY {
+            v -= ply as i32;
+        }
+        if ply % 2 == 1 {
+            v = -v;
+        }
+
+        let m = s.pv[ply];
+        if let Some(rm) = root_moves.iter_mut().find(|rm| rm.pv[0] == m) {
+            rm.score = v;
+            rm.pv = s.pv[ply..].to_vec();
+        }
+    }
+    root_moves.sort();
+
+    std::cmp::max(s.depth - ply as i32 * ONE_PLY, Depth::ZERO)
+}
+
 pub fn set_stop(b: bool) {
     STOP.store(b, Ordering::SeqCst);
+    signal();
 }
 
 pub fn set_ponder(b: bool) {
     PONDER.store(b, Ordering::SeqCst);
+    signal();
+}
+
+// signal() wakes up a thread blocked in wait_for_stop(). Taking the lock
+// after the flag has been stored guarantees that the waiter either sees the
+// new value or is already waiting when it is notified.
+
+fn signal() {
+    let _lock = SIGNAL_LOCK.lock().unwrap();
+    SIGNAL_CV.notify_all();
+}
+
+// wait_for_stop() blocks until the search is stopped or, for a search that
+// is not infinite, until pondering has been switched off by "ponderhit".
+
+pub fn wait_for_stop(infinite: bool) {
+    let mut lock = SIGNAL_LOCK.lock().unwrap();
+    while !stop() && (ponder() || infinite) {
+        lock = SIGNAL_CV.wait(lock).unwrap();
+    }
 }
 
 pub fn set_stop_on_ponderhit(b: bool) {
     STOP_ON_PONDERHIT.store(b, Ordering::SeqCst);
 }
 
-pub fn init(requested: usize) {
-    let handlers: Box<Handlers> = Box::new(Vec::new());
-    let threads: Box<Threads> = Box::new(Vec::new());
-    unsafe {
-        HANDLERS = Box::into_raw(handlers);
-        THREADS = Box::into_raw(threads);
-    }
+pub fn set_main_only(b: bool) {
+    MAIN_ONLY.store(b, Ordering::Relaxed);
+}
 
+pub fn init(requested: usize) {
     set(requested);
 }
 
 pub fn free() {
     set(0);
-    unsafe {
-        std::mem::drop(Box::from_raw(HANDLERS));
-        std::mem::drop(Box::from_raw(THREADS));
-    }
 }
 
 pub fn set(requested: usize) {
-    let mut handlers = unsafe { Box::from_raw(HANDLERS) };
-    let mut threads = unsafe { Box::from_raw(THREADS) };
+    let mut handlers = HANDLERS.lock().unwrap();
+
+    let binding = if handlers.len() >= requested {
+        numa::Binding::Unbound
+    } else {
+        match numa::Binding::parse(&ucioption::get_string("Thread Binding")) {
+            Some(binding) => binding,
+            None => {
+                println!("info string Invalid thread binding, threads not \
+                    bound");
+                numa::Binding::Unbound
+            }
+        }
+    };
 
     while handlers.len() < requested {
         let idx = handlers.len();
+        let cpus = numa::cpus_for_thread(&binding, idx);
         let (tx, rx) = channel();
         // 16 MB stacks are now too small in debug mode, so use 32 MB stacks
         let builder = thread::Builder::new().stack_size(32 * 1024 * 1024);
-        let handler = builder.spawn(move || run_thread(idx, tx)).unwrap();
+        let handler =
+            builder.spawn(move || run_thread(idx, cpus, tx)).unwrap();
         let th = rx.recv().unwrap();
+        // Wait until the thread is idle, or it would miss a wake-up sent
+        // before it got there
+        wait_for_idle(&th);
         handlers.push(handler);
-        threads.push(th);
+        THREADS.write().unwrap().push(th);
     }
 
     while handlers.len() > requested {
         let handler = handlers.pop().unwrap();
-        let th = threads.pop().unwrap();
+        let th = THREADS.write().unwrap().pop().unwrap();
+        // A thread still searching would take the exit for a search
+        wait_for_idle(&th);
         wake_up(&th, true, false);
         let _ = handler.join();
     }
-
-    std::mem::forget(handlers);
-    std::mem::forget(threads);
 }
 
-fn run_thread(idx: usize, tx: Sender<Arc<ThreadCtrl>>) {
-    let mut pos = Box::new(Position::new());
-    pos.pawns_table.reserve_exact(16384);
-    for _ in 0..16384 {
-        pos.pawns_table.push(std::cell::UnsafeCell::new(pawns::Entry::new()));
+fn wait_for_idle(th: &ThreadCtrl) {
+    let mut state = th.state.lock().unwrap();
+    while state.searching {
+        state = th.cv.wait(state).unwrap();
     }
-    pos.material_table.reserve_exact(8192);
-    for _ in 0..8192 {
-        pos.material_table
-            .push(std::cell::UnsafeCell::new(material::Entry::new()));
+}
+
+// rebind() restarts the search threads so that a new "Thread Binding" is
+// applied. The threads' history tables are lost.
+
+pub fn rebind() {
+    let n = HANDLERS.lock().unwrap().len();
+    set(0);
+    set(n);
+}
+
+fn run_thread(
+    idx: usize, cpus: Option<Vec<usize>>, tx: Sender<Arc<ThreadCtrl>>
+) {
+    // Bind the thread before allocating its tables, so that they are placed
+    // on the thread's NUMA node.
+    if let Some(cpus) = cpus {
+        if !numa::bind(&cpus) {
+            println!("info string Unable to bind thread {}", idx);
+        }
     }
+
+    let mut pos = Box::new(Position::new());
+    let (pawn_entries, material_entries) = table_sizes();
+    pos.init_tables(pawn_entries, material_entries);
     pos.is_main = idx == 0;
     pos.thread_idx = idx as i32;
     let th = Arc::new(ThreadCtrl::new(idx));
@@ -190,7 +481,8 @@ fn run_thread(idx: usize, tx: Sender<Arc<ThreadCtrl>>) {
             break;
         }
         if state.clear {
-            // Clear this thread as part of ucinewgame
+            // Clear this thread and its part of the TT as part of ucinewgame
+            tt::clear_part(th.idx, count());
             if th.idx == 0 {
                 pos.previous_score = Value::INFINITE;
                 pos.previous_time_reduction = 1.;
@@ -203,6 +495,11 @@ fn run_thread(idx: usize, tx: Sender<Arc<ThreadCtrl>>) {
             state.clear = false;
             continue;
         }
+        if state.clear_tt {
+            tt::clear_part(th.idx, count());
+            state.clear_tt = false;
+            continue;
+        }
         {
             let common = th.common.lock().unwrap();
             let pos_data = common.pos_data.read().unwrap();
@@ -218,24 +515,155 @@ fn run_thread(idx: usize, tx: Sender<Arc<ThreadCtrl>>) {
         } // Locks are dropped here
         pos.nodes = 0;
         pos.tb_hits = 0;
+
+        // Apply new pawn and material hash table sizes between searches
+        let (pawn_entries, material_entries) = table_sizes();
+        if pawn_entries != pos.pawns_table.len()
+            || material_entries != pos.material_table.len()
+        {
+            pos.init_tables(pawn_entries, material_entries);
+        }
+        pos.table_stats.set(TableStats::default());
+        #[cfg(feature = "searchstats")]
+        {
+            pos.stats = SearchStats::default();
+        }
+
         if th.idx == 0 {
             mainthread_search(&mut pos, &th);
         } else {
             thread_search(&mut pos, &th);
-            let lock = th.common.lock().unwrap();
-            let result = &mut lock.result.lock().unwrap();
-            if pos.root_moves[0].score > result.score
-                && (pos.completed_depth >= result.depth
-                    || pos.root_moves[0].score >= Value::MATE_IN_MAX_PLY)
-            {
-                result.depth = pos.completed_depth;
-                result.score = pos.root_moves[0].score;
-                result.pv = pos.root_moves[0].pv.clone();
-            }
+            set_result(&th, &pos);
+        }
+        *th.table_stats.lock().unwrap() = pos.table_stats.get();
+        #[cfg(feature = "searchstats")]
+        {
+            *th.search_stats.lock().unwrap() = pos.stats;
         }
     }
 }
 
+// set_result() keeps the best line of a thread at the end of its search
+
+pub fn set_result(th: &ThreadCtrl, pos: &Position) {
+    *th.result.lock().unwrap() = SearchResult {
+        depth: pos.completed_depth,
+        score: pos.root_moves[0].score,
+        pv: pos.root_moves[0].pv.clone(),
+    };
+}
+
+// results() returns the best lines of all threads in the last search, the
+// main thread's first. Threads that completed no iteration are left out.
+
+pub fn results() -> Vec<SearchResult> {
+    threads().iter().filter_map(|th| {
+        let r = th.result.lock().unwrap();
+        if r.depth == Depth::ZERO || r.pv.is_empty() || r.pv[0] == Move::NONE
+        {
+            None
+        } else {
+            Some(SearchResult {
+                depth: r.depth,
+                score: r.score,
+                pv: r.pv.clone(),
+            })
+        }
+    }).collect()
+}
+
+// pick_by_score() chooses among the results of the threads as done before
+// voting: a helper replaces the main thread's line only with a better score
+// at the same or a greater depth, or with a mate score.
+
+pub fn pick_by_score(results: &[SearchResult]) -> usize {
+    let mut best = 0;
+    for (i, r) in results.iter().enumerate().skip(1) {
+        if r.score > results[best].score
+            && (r.depth >= results[best].depth
+                || r.score >= Value::MATE_IN_MAX_PLY)
+        {
+            best = i;
+        }
+    }
+    best
+}
+
+// pick_by_votes() chooses among the results of the threads by vote. Every
+// thread votes for its best move with a weight growing with its completed
+// depth and with its score above the worst score of all threads, and the
+// first thread whose move has the most votes wins. A proven mate overrides
+// the vote, the shortest one being taken.
+
+pub fn pick_by_votes(results: &[SearchResult]) -> usize {
+    let mated = results.iter().enumerate()
+        .filter(|&(_, r)| r.score >= Value::MATE_IN_MAX_PLY)
+        .max_by_key(|&(i, r)| (r.score, std::cmp::Reverse(i)));
+    if let Some((i, _)) = mated {
+        return i;
+    }
+
+    let min_score = results.iter().map(|r| r.score).min()
+        .unwrap_or(Value::ZERO);
+    let mut votes: Vec<(Move, i64)> = Vec::new();
+    for r in results.iter() {
+        let weight = (r.score - min_score + Value(14)).0 as i64
+            * (r.depth / ONE_PLY) as i64;
+        match votes.iter_mut().find(|v| v.0 == r.pv[0]) {
+            Some(v) => v.1 += weight,
+            None => votes.push((r.pv[0], weight)),
+        }
+    }
+    let votes_for = |m: Move| votes.iter().find(|v| v.0 == m).unwrap().1;
+
+    let mut best = 0;
+    for (i, r) in results.iter().enumerate() {
+        if votes_for(r.pv[0]) > votes_for(results[best].pv[0]) {
+            best = i;
+        }
+    }
+    best
+}
+
+// table_sizes() returns the number of entries of the pawn and material hash
+// tables as set by the options, rounded down to a power of 2.
+
+pub fn table_sizes() -> (usize, usize) {
+    (floor_pow2(ucioption::get_i32("Pawn Hash Entries")),
+        floor_pow2(ucioption::get_i32("Material Hash Entries")))
+}
+
+pub fn floor_pow2(n: i32) -> usize {
+    1usize << (31 - std::cmp::max(n, 1).leading_zeros())
+}
+
+// table_stats() returns the pawn and material hash table statistics of the
+// last search, summed over all threads.
+
+pub fn table_stats() -> TableStats {
+    let mut stats = TableStats::default();
+
+    for th in threads().iter() {
+        stats.add(&th.table_stats.lock().unwrap());
+    }
+
+    stats
+}
+
+// search_stats() returns the search statistics of the last search, summed
+// over all threads.
+
+#[cfg(feature = "searchstats")]
+pub fn search_stats() -> SearchStats {
+    let mut stats = SearchStats::default();
+
+    for th in threads().iter() {
+        stats.add(&th.search_stats.lock().unwrap());
+    }
+
+    stats
+}
+
 fn wake_up(th: &ThreadCtrl, exit: bool, clear: bool)
 {
     let mut state = th.state.lock().unwrap();
@@ -245,33 +673,55 @@ fn wake_up(th: &ThreadCtrl, exit: bool, clear: bool)
     th.cv.notify_one();
 }
 
+// count() returns the number of search threads
+
+pub fn count() -> usize {
+    THREADS.read().unwrap().len()
+}
+
 pub fn wake_up_slaves()
 {
-    let threads: Box<Threads> = unsafe { Box::from_raw(THREADS) };
+    if MAIN_ONLY.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let threads = threads();
 
     for th in threads.iter() {
         if th.idx != 0 {
             wake_up(th, false, false);
         }
     }
-
-    std::mem::forget(threads);
 }
 
 pub fn clear_search()
 {
-    let threads: Box<Threads> = unsafe { Box::from_raw(THREADS) };
+    let threads = threads();
 
     for th in threads.iter() {
         wake_up(th, false, true);
     }
+}
+
+// clear_tt() makes each search thread clear its part of the TT, so that the
+// table is cleared in parallel and its pages stay where the threads first
+// touched them. Unlike clear_search() it keeps the histories.
 
-    std::mem::forget(threads);
+pub fn clear_tt()
+{
+    let threads = threads();
+
+    for th in threads.iter() {
+        let mut state = th.state.lock().unwrap();
+        state.searching = true;
+        state.clear_tt = true;
+        th.cv.notify_one();
+    }
 }
 
 pub fn wait_for_main()
 {
-    let threads: Box<Threads> = unsafe { Box::from_raw(THREADS) };
+    let threads = threads();
 
     for th in threads.iter() {
         if th.idx == 0 {
@@ -281,13 +731,11 @@ pub fn wait_for_main()
             }
         }
     }
-
-    std::mem::forget(threads);
 }
 
 pub fn wait_for_slaves()
 {
-    let threads: Box<Threads> = unsafe { Box::from_raw(THREADS) };
+    let threads = threads();
 
     for th in threads.iter() {
         if th.idx != 0 {
@@ -297,13 +745,11 @@ pub fn wait_for_slaves()
             }
         }
     }
-
-    std::mem::forget(threads);
 }
 
 pub fn wait_for_all()
 {
-    let threads: Box<Threads> = unsafe { Box::from_raw(THREADS) };
+    let threads = threads();
 
     for th in threads.iter() {
         let mut state = th.state.lock().unwrap();
@@ -311,15 +757,13 @@ pub fn wait_for_all()
             state = th.cv.wait(state).unwrap();
         }
     }
-
-    std::mem::forget(threads);
 }
 
 pub fn start_thinking(
     pos: &mut Position, pos_data: &Arc<RwLock<PosData>>, limits: &LimitsType,
-    searchmoves: Vec<Move>, ponder_mode: bool
+    searchmoves: Vec<Move>, excludemoves: Vec<Move>, ponder_mode: bool
 ) {
-    let threads: Box<Threads> = unsafe { Box::from_raw(THREADS) };
+    let threads = threads();
 
     wait_for_main();
 
@@ -331,63 +775,160 @@ pub fn start_thinking(
         LIMITS = (*limits).clone();
     }
 
+    // Excluded moves are removed before the tablebase ranking, so that the
+    // ranking and MultiPV only see the moves that are searched
     let mut root_moves = RootMoves::new();
     for m in MoveList::new::<Legal>(pos) {
-        if searchmoves.is_empty()
-            || searchmoves.iter().any(|&x| x == m)
+        if (searchmoves.is_empty()
+            || searchmoves.iter().any(|&x| x == m))
+            && !excludemoves.contains(&m)
         {
             root_moves.push(RootMove::new(m));
         }
     }
 
+    seed_root_moves(pos, &mut root_moves);
+
+    let start_depth =
+        if limits.infinite || ucioption::get_bool("UCI_AnalyseMode") {
+            resume_analysis(pos, &mut root_moves)
+        } else {
+            Depth::ZERO
+        };
+
     tb::read_options();
     tb::rank_root_moves(pos, &mut root_moves);
 
     let root_moves = Arc::new(root_moves);
-    let result = Arc::new(Mutex::new(SearchResult {
-        depth: Depth::ZERO,
-        score: -Value::INFINITE,
-        pv: Vec::new(),
-    }));
-
     for th in threads.iter() {
-        th.nodes.set(0);
-        th.tb_hits.set(0);
+        th.nodes.store(0, Ordering::Relaxed);
+        th.tb_hits.store(0, Ordering::Relaxed);
         let mut common = th.common.lock().unwrap();
         common.root_moves = root_moves.clone();
         common.pos_data = pos_data.clone();
-        common.result = result.clone();
+        *th.result.lock().unwrap() = SearchResult::none();
+        common.start_depth = start_depth;
     }
 
-    wake_up(&threads[0], false, false);
+    mcts::clear();
 
-    std::mem::forget(threads);
+    cluster::start_search(pos_data, limits, &searchmoves, &excludemoves,
+        pos.is_chess960());
+
+    wake_up(&threads[0], false, false);
 }
 
 pub fn nodes_searched() -> u64 {
-    let threads: Box<Threads> = unsafe { Box::from_raw(THREADS) };
+    let threads = THREADS.read().unwrap();
 
     let mut nodes = 0;
 
     for th in threads.iter() {
-        nodes += th.nodes.get();
+        nodes += th.nodes.load(Ordering::Relaxed);
     }
 
-    std::mem::forget(threads);
-
-    nodes
+    nodes + cluster::nodes()
 }
 
 pub fn tb_hits() -> u64 {
-    let threads: Box<Threads> = unsafe { Box::from_raw(THREADS) };
+    let threads = THREADS.read().unwrap();
 
     let mut tb_hits = 0;
 
     for th in threads.iter() {
-        tb_hits += th.tb_hits.get();
+        tb_hits += th.tb_hits.load(Ordering::Relaxed);
     }
 
-    std::mem::forget(threads);
-
     tb_hits
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(depth: i32, score: Value, m: Move) -> SearchResult {
+        SearchResult {
+            depth: Depth(depth),
+            score,
+            pv: vec![m],
+        }
+    }
+
+    fn moves() -> (Move, Move, Move) {
+        (Move::make(Square::E2, Square::E4), Move::make(Square::D2, Square::D4),
+            Move::make(Square::G1, Square::F3))
+    }
+
+    #[test]
+    fn mate_overrides_vote() {
+        let (a, b, c) = moves();
+        let results = [result(20, Value(50), a), result(20, Value(50), a),
+            result(10, mate_in(5), b), result(8, mate_in(3), c)];
+        // The shortest mate wins, even from the shallowest thread
+        assert_eq!(pick_by_votes(&results), 3);
+        assert_eq!(pick_by_score(&results), 3);
+    }
+
+    #[test]
+    fn majority_beats_higher_score() {
+        let (a, b, _) = moves();
+        let results = [result(12, Value(60), a), result(12, Value(40), b),
+            result(12, Value(40), b), result(12, Value(35), b)];
+        assert_eq!(pick_by_votes(&results), 1);
+        assert_eq!(pick_by_score(&results), 0);
+    }
+
+    #[test]
+    fn shallower_better_score_is_ignored_by_score() {
+        let (a, b, _) = moves();
+        let results = [result(12, Value(20), a), result(11, Value(90), b)];
+        assert_eq!(pick_by_score(&results), 0);
+        let results = [result(12, Value(20), a), result(12, Value(90), b)];
+        assert_eq!(pick_by_score(&results), 1);
+    }
+
+    #[test]
+    fn ties_go_to_the_first_thread() {
+        let (a, b, c) = moves();
+        let results = [result(10, Value(20), a), result(10, Value(20), b)];
+        assert_eq!(pick_by_votes(&results), 0);
+        assert_eq!(pick_by_score(&results), 0);
+
+        // A tie between helpers goes to the first of them
+        let results = [result(10, Value(0), a), result(10, Value(30), b),
+            result(10, Value(30), c)];
+        assert_eq!(pick_by_votes(&results), 1);
+        assert_eq!(pick_by_score(&results), 1);
+
+        // Of two equally short mates the first thread's is taken
+        let results = [result(10, Value(0), a), result(9, mate_in(3), b),
+            result(9, mate_in(3), c)];
+        assert_eq!(pick_by_votes(&results), 1);
+    }
+
+    // Run under Miri or ThreadSanitizer, this also checks that reading the
+    // node counters while the threads update them is free of data races
+    #[test]
+    fn node_counts_while_searching() {
+        let ctrls: Vec<Arc<ThreadCtrl>> =
+            (0..4).map(|idx| Arc::new(ThreadCtrl::new(idx))).collect();
+        THREADS.write().unwrap().extend(ctrls.iter().cloned());
+
+        let handles: Vec<_> = ctrls.iter().cloned().map(|th| {
+            std::thread::spawn(move || for n in 1..=100 {
+                th.nodes.store(n, Ordering::Relaxed);
+            })
+        }).collect();
+        let mut last = 0;
+        while last < 400 {
+            let nodes = nodes_searched();
+            assert!(nodes >= last && nodes <= 400);
+            last = nodes;
+        }
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        THREADS.write().unwrap().clear();
+    }
+}
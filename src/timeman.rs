@@ -1,14 +1,37 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use json;
 use search;
+use search::DelayType;
+use threads;
 use types::*;
 use ucioption;
 
 use std;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
 
 static mut START_TIME: Option<std::time::Instant> = None;
 static mut OPTIMUM_TIME: i64 = 0;
 static mut MAXIMUM_TIME: i64 = 0;
+static mut MINIMUM_TIME: i64 = 0;
+
+// Time spent pondering before "ponderhit". Our clock only started running
+// at "ponderhit", so this time is credited to us: it counts as thinking time
+// for the optimum but not as time used from the clock for the maximum.
+static mut PONDER_TIME: i64 = 0;
+
+// With the "nodestime" option set, time is measured in searched nodes: every
+// millisecond corresponds to NODES_TIME nodes. The node budget left on each
+// side's clock is kept in AVAILABLE_NODES, indexed by colour, so that both
+// sides can be played by the engine, as in "analyse" or self-play. A budget
+// is None until the first search of that side in a game.
+static mut NODES_TIME: i64 = 0;
+static mut AVAILABLE_NODES: [Option<i64>; 2] = [None; 2];
+
+// The iterations of the current search as recorded for the "TimeLog" file
+static ITERATIONS: Mutex<Vec<String>> = Mutex::new(Vec::new());
 
 pub fn optimum() -> i64 {
     unsafe { OPTIMUM_TIME }
@@ -18,7 +41,32 @@ pub fn maximum() -> i64 {
     unsafe { MAXIMUM_TIME }
 }
 
+pub fn minimum() -> i64 {
+    unsafe { MINIMUM_TIME }
+}
+
+pub fn pondered() -> i64 {
+    unsafe { PONDER_TIME }
+}
+
+// ponderhit() is called when the GUI sends "ponderhit" and records the time
+// spent pondering.
+
+pub fn ponderhit() {
+    unsafe {
+        PONDER_TIME = elapsed();
+    }
+}
+
+// elapsed() returns the milliseconds spent in the current search, or with
+// "nodestime" the virtual milliseconds corresponding to the nodes searched.
+
 pub fn elapsed() -> i64 {
+    let nodes_time = unsafe { NODES_TIME };
+    if nodes_time > 0 {
+        return threads::nodes_searched() as i64 / nodes_time;
+    }
+
     let duration = unsafe { START_TIME.unwrap().elapsed() };
     (duration.as_secs() * 1000 + (duration.subsec_nanos() / 1000000) as u64)
         as i64
@@ -72,30 +120,82 @@ fn remaining(
     (my_time as f64 * ratio1.min(ratio2)) as i64
 }
 
-// init() is called at the beginning of the search and calculates the allowed
-// thinking time out of the time control and current game ply. We support
-// four different kinds of time controls, passed in 'limits':
+// budget() calculates the optimum, maximum and minimum thinking time out of
+// the time control and current game ply. We support these kinds of time
+// controls, passed in 'limits':
 //
 //  inc == 0 && movestogo == 0 means: x basetime  [sudden death!]
 //  inc == 0 && movestogo != 0 means: x moves in y minutes
-//  inc >  0 && movestogo == 0 means: x basetime + z increment
+//  inc >  0 && movestogo == 0 means: x basetime + z increment  [Fischer]
 //  inc >  0 && movestogo != 0 means: x moves in y minutes + z increment
+//
+// and either of these may instead have a delay of d per move:
+//
+//  delay_type == Simple    means: the clock only starts after d [US delay]
+//  delay_type == Bronstein means: after the move, the time used is given
+//                                 back, up to d
+//
+// In both delay controls thinking up to d costs nothing, so we always use
+// at least d (the minimum). With a simple delay the delay of the current
+// move is also available on top of the clock. Fixed time per move with a
+// reserve is passed as a simple delay on a clock holding the reserve.
+//
+// After the main time 'time' has run out, overtime periods of 'byoyomi'
+// may follow:
+//
+//  period_moves == 0 means: each move must be made within the period
+//                           [Japanese byo-yomi]
+//  period_moves >  0 means: period_moves moves in the period, 'byoyomi'
+//                           is the time left in the current period and
+//                           movestogo the moves left in it [Canadian]
+//
+// Japanese byo-yomi works like a simple delay. We never plan to use up a
+// period, so spare periods are kept for emergencies only.
 
-pub fn init(limits: &mut search::LimitsType, us: Color, ply: i32)
-{
-    let min_think_time = ucioption::get_i32("Minimum Thinking Time") as i64;
-    let move_overhead  = ucioption::get_i32("Move Overhead") as i64;
-    let slow_mover     = ucioption::get_i32("Slow Mover") as i64;
+pub fn budget(
+    limits: &search::LimitsType, us: Color, ply: i32, min_think_time: i64,
+    move_overhead: i64, slow_mover: i64, ponder: bool
+) -> (i64, i64, i64) {
+    let mut my_time = limits.time[us.0 as usize];
+    let mut movestogo = limits.movestogo;
+    let delay = limits.delay[us.0 as usize];
+    let byoyomi = limits.byoyomi[us.0 as usize];
 
-    unsafe {
-        START_TIME = limits.start_time;
-        let time = std::cmp::max(limits.time[us.0 as usize], min_think_time);
-        OPTIMUM_TIME = time;
-        MAXIMUM_TIME = time;
+    // Time that is effectively added for every future move
+    let mut inc = limits.inc[us.0 as usize] + delay;
+
+    // Time available for the current move on top of the clock
+    let mut bonus = 0;
+
+    // Time that can be used for the current move without any cost
+    let mut free = delay;
+
+    if delay > 0 && limits.delay_type == DelayType::Simple {
+        bonus = delay;
+    }
+
+    if byoyomi > 0 && limits.period_moves == 0 {
+        // Japanese byo-yomi: every move may use a full period for free
+        inc += byoyomi;
+        bonus += byoyomi;
+        free = std::cmp::max(free, byoyomi);
+    } else if byoyomi > 0 && my_time > 0 {
+        // Canadian overtime, still in main time: the overtime period adds
+        // its share of time to each of the moves after the main time.
+        inc += byoyomi / limits.period_moves as i64;
+    } else if byoyomi > 0 {
+        // Canadian overtime: the moves left in the period share its time
+        my_time = byoyomi;
+        if movestogo == 0 {
+            movestogo = limits.period_moves;
+        }
     }
 
-    let max_mtg = if limits.movestogo != 0
-        { std::cmp::min(limits.movestogo, MOVE_HORIZON) }
+    let mut optimum = std::cmp::max(my_time + bonus, min_think_time);
+    let mut maximum = optimum;
+
+    let max_mtg = if movestogo != 0
+        { std::cmp::min(movestogo, MOVE_HORIZON) }
     else
         { MOVE_HORIZON };
 
@@ -104,8 +204,8 @@ pub fn init(limits: &mut search::LimitsType, us: Color, ply: i32)
     // the greates hyp_mtg givse the minimum values.
     for hyp_mtg in 1..(max_mtg + 1) {
         // Calculate thinking time for hypothetical "moves to go" value
-        let mut hyp_my_time = limits.time[us.0 as usize]
-            + limits.inc[us.0 as usize] * (hyp_mtg - 1) as i64
+        let mut hyp_my_time = my_time + bonus
+            + inc * (hyp_mtg - 1) as i64
             - move_overhead * (2 + std::cmp::min(hyp_mtg, 40) as i64);
 
         hyp_my_time = std::cmp::max(hyp_my_time, 0);
@@ -115,15 +215,348 @@ pub fn init(limits: &mut search::LimitsType, us: Color, ply: i32)
         let t2 = min_think_time
             + remaining(hyp_my_time, hyp_mtg, ply, slow_mover, MaxTime);
 
+        optimum = std::cmp::min(t1, optimum);
+        maximum = std::cmp::min(t2, maximum);
+    }
+
+    if ponder {
+        optimum += optimum / 4;
+    }
+
+    // Never lose a period, or the game with a delay, to the move overhead
+    if byoyomi > 0 || delay > 0 {
+        maximum = std::cmp::min(maximum,
+            std::cmp::max(my_time + bonus - move_overhead, 0));
+        optimum = std::cmp::min(optimum, maximum);
+    }
+
+    // Never leave time unused that comes for free
+    let minimum = std::cmp::min(std::cmp::max(free - move_overhead, 0),
+        maximum);
+    optimum = std::cmp::max(optimum, minimum);
+
+    (optimum, maximum, minimum)
+}
+
+// init() is called at the beginning of the search and sets the allowed
+// thinking time for the time control in 'limits', see budget().
+
+pub fn init(limits: &mut search::LimitsType, us: Color, ply: i32)
+{
+    let min_think_time = ucioption::get_i32("Minimum Thinking Time") as i64;
+    let move_overhead  = ucioption::get_i32("Move Overhead") as i64;
+    let slow_mover     = ucioption::get_i32("Slow Mover") as i64;
+
+    let nodes_time     = ucioption::get_i32("nodestime") as i64;
+
+    // With "nodestime" we play on our own node clock instead of the GUI's
+    // clock, which is converted into a node budget at the start of a game.
+    if nodes_time > 0 && limits.use_time_management() {
         unsafe {
-            OPTIMUM_TIME = std::cmp::min(t1, OPTIMUM_TIME);
-            MAXIMUM_TIME = std::cmp::min(t2, MAXIMUM_TIME);
+            let mut available = AVAILABLE_NODES;
+            node_clock(&mut available, limits, us, nodes_time);
+            AVAILABLE_NODES = available;
         }
     }
 
-    if ucioption::get_bool("Ponder") {
-        unsafe {
-            OPTIMUM_TIME += OPTIMUM_TIME / 4;
+    let (optimum, maximum, minimum) = budget(limits, us, ply, min_think_time,
+        move_overhead, slow_mover, ucioption::get_bool("Ponder"));
+
+    unsafe {
+        START_TIME = limits.start_time;
+        OPTIMUM_TIME = optimum;
+        MAXIMUM_TIME = maximum;
+        MINIMUM_TIME = minimum;
+        NODES_TIME = nodes_time;
+        PONDER_TIME = 0;
+    }
+
+    ITERATIONS.lock().unwrap().clear();
+}
+
+// search_done() is called after each search. With "nodestime" it charges the
+// nodes searched to our node budget and credits the increment. Nodes searched
+// within a delay are free.
+
+pub fn search_done(limits: &search::LimitsType, us: Color) {
+    unsafe {
+        if NODES_TIME == 0 || !limits.use_time_management() {
+            return;
         }
+        let mut available = AVAILABLE_NODES;
+        charge_nodes(&mut available, limits, us, NODES_TIME,
+            threads::nodes_searched() as i64);
+        AVAILABLE_NODES = available;
+    }
+}
+
+// node_clock() replaces our clock time in 'limits' by the node budget left
+// for our side, starting the budget from the clock on our first search.
+
+fn node_clock(
+    available: &mut [Option<i64>; 2], limits: &mut search::LimitsType,
+    us: Color, nodes_time: i64,
+) {
+    let nodes = available[us.0 as usize]
+        .unwrap_or(limits.time[us.0 as usize] * nodes_time);
+    available[us.0 as usize] = Some(nodes);
+    limits.time[us.0 as usize] = nodes / nodes_time;
+}
+
+// charge_nodes() charges the 'used' nodes, less those searched within the
+// delay, to our node budget and credits the increment.
+
+fn charge_nodes(
+    available: &mut [Option<i64>; 2], limits: &search::LimitsType,
+    us: Color, nodes_time: i64, used: i64,
+) {
+    if let Some(nodes) = available[us.0 as usize] {
+        let free = limits.delay[us.0 as usize] * nodes_time;
+        let left = nodes + limits.inc[us.0 as usize] * nodes_time
+            - std::cmp::max(used - free, 0);
+        available[us.0 as usize] = Some(std::cmp::max(left, 0));
+    }
+}
+
+// The "TimeLog" option names a file to which one JSON line is appended for
+// every move searched under time management. It records the limits the
+// budget was calculated from, the budget, the factors computed after each
+// iteration to decide whether to stop and the time actually used. These
+// logs can be replayed with other parameters by "rustfish tmreplay".
+
+pub fn log_enabled() -> bool {
+    let file = ucioption::get_string("TimeLog");
+    !file.is_empty() && file != "<empty>"
+}
+
+// log_iteration() records the time management state after an iteration.
+// 'stop_factor' is the smallest fraction of the optimum time after which
+// the search would have been stopped.
+
+pub fn log_iteration(
+    depth: Depth, best_move_changes: f64, failed_low: bool,
+    improving_factor: i32, time_reduction: f64, unstable_pv_factor: f64,
+    stop_factor: f64
+) {
+    let iteration = json::Object::new("iteration")
+        .num("depth", depth / ONE_PLY)
+        .num("elapsed", elapsed())
+        .num("best_move_changes", best_move_changes)
+        .raw("failed_low", if failed_low { "true" } else { "false" })
+        .num("improving_factor", improving_factor)
+        .num("time_reduction", time_reduction)
+        .num("unstable_pv_factor", unstable_pv_factor)
+        .num("stop_factor", stop_factor)
+        .end();
+    ITERATIONS.lock().unwrap().push(iteration);
+}
+
+// write_log() appends the record of the search that has just finished to
+// the "TimeLog" file.
+
+pub fn write_log(limits: &search::LimitsType, us: Color, ply: i32) {
+    if !log_enabled() || !limits.use_time_management() {
+        return;
+    }
+
+    let iterations = std::mem::take(&mut *ITERATIONS.lock().unwrap());
+    let record = json::Object::new("move")
+        .num("ply", ply)
+        .str("side", if us == WHITE { "w" } else { "b" })
+        .nums("time", &limits.time)
+        .nums("inc", &limits.inc)
+        .num("movestogo", limits.movestogo)
+        .nums("delay", &limits.delay)
+        .str("delay_type", match limits.delay_type {
+            DelayType::Simple => "simple",
+            DelayType::Bronstein => "bronstein",
+        })
+        .nums("byoyomi", &limits.byoyomi)
+        .num("period_moves", limits.period_moves)
+        .raw("ponder",
+            if ucioption::get_bool("Ponder") { "true" } else { "false" })
+        .num("optimum", optimum())
+        .num("maximum", maximum())
+        .num("minimum", minimum())
+        .num("used", elapsed() - pondered())
+        .num("pondered", pondered())
+        .raw("iterations", &format!("[{}]", iterations.join(",")))
+        .end();
+
+    let file = ucioption::get_string("TimeLog");
+    let written = OpenOptions::new().create(true).append(true).open(&file)
+        .and_then(|mut f| writeln!(f, "{}", record));
+    if written.is_err() {
+        println!("info string Unable to write time log {}", file);
+    }
+}
+
+// new_game() forgets the node budget, so that the next search starts a new
+// node clock from the GUI's clock.
+
+pub fn new_game() {
+    unsafe {
+        AVAILABLE_NODES = [None; 2];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIN_THINK_TIME: i64 = 20;
+    const MOVE_OVERHEAD: i64 = 30;
+    const SLOW_MOVER: i64 = 89;
+
+    fn budget_for(limits: &search::LimitsType, ply: i32) -> (i64, i64, i64) {
+        budget(limits, WHITE, ply, MIN_THINK_TIME, MOVE_OVERHEAD, SLOW_MOVER,
+            false)
+    }
+
+    fn clock(time: i64) -> search::LimitsType {
+        let mut limits = search::LimitsType::new();
+        limits.time = [time; 2];
+        limits
+    }
+
+    #[test]
+    fn sudden_death() {
+        let (optimum, maximum, minimum) = budget_for(&clock(60000), 20);
+        assert!(optimum > 0 && optimum < 60000 / 20);
+        assert!(maximum >= optimum && maximum < 60000);
+        assert_eq!(minimum, 0);
+    }
+
+    #[test]
+    fn moves_to_go() {
+        let mut limits = clock(60000);
+        limits.movestogo = 1;
+        let (_, last, _) = budget_for(&limits, 20);
+        limits.movestogo = 40;
+        let (optimum, maximum, _) = budget_for(&limits, 20);
+        assert!(last > maximum && last <= 60000);
+        assert!(optimum <= 60000 / 40 * 2);
+    }
+
+    #[test]
+    fn fischer() {
+        let mut limits = clock(60000);
+        let (base, _, _) = budget_for(&limits, 20);
+        limits.inc = [2000; 2];
+        let (optimum, maximum, minimum) = budget_for(&limits, 20);
+        assert!(optimum > base);
+        assert!(maximum < 60000);
+        assert_eq!(minimum, 0);
+    }
+
+    #[test]
+    fn simple_delay() {
+        // Little time left on the clock, but the delay is free
+        let mut limits = clock(1000);
+        limits.delay = [5000; 2];
+        limits.delay_type = search::DelayType::Simple;
+        let (optimum, maximum, minimum) = budget_for(&limits, 60);
+        assert_eq!(minimum, 5000 - MOVE_OVERHEAD);
+        assert!(optimum >= minimum);
+        assert!(maximum > 1000 && maximum <= 1000 + 5000);
+    }
+
+    #[test]
+    fn bronstein_delay() {
+        let mut limits = clock(60000);
+        limits.delay = [3000; 2];
+        limits.delay_type = search::DelayType::Bronstein;
+        let (optimum, maximum, minimum) = budget_for(&limits, 20);
+        assert_eq!(minimum, 3000 - MOVE_OVERHEAD);
+        assert!(optimum >= minimum && maximum < 60000);
+
+        // The delay is only given back after the move, so the clock alone
+        // limits the current move.
+        limits.time = [1000; 2];
+        let (optimum, maximum, minimum) = budget_for(&limits, 60);
+        assert!(maximum < 1000);
+        assert!(minimum <= maximum && optimum <= maximum);
+    }
+
+    #[test]
+    fn fixed_time_with_reserve() {
+        // 10 s per move with a reserve of 60 s, as set up by "go movetime
+        // 10000 wreserve 60000 breserve 60000"
+        let mut limits = clock(60000);
+        limits.delay = [10000; 2];
+        let (optimum, maximum, minimum) = budget_for(&limits, 20);
+        assert_eq!(minimum, 10000 - MOVE_OVERHEAD);
+        assert!(optimum >= minimum && maximum > 10000);
+        assert!(maximum < 10000 + 60000);
+
+        // Without a reserve we still use the whole move time
+        limits.time = [0; 2];
+        let (optimum, maximum, minimum) = budget_for(&limits, 20);
+        assert!(maximum > 10000 - 4 * MOVE_OVERHEAD && maximum < 10000);
+        assert!(optimum == maximum && minimum == maximum);
+    }
+
+    #[test]
+    fn japanese_byoyomi() {
+        let mut limits = clock(60000);
+        limits.byoyomi = [10000; 2];
+        let (optimum, maximum, minimum) = budget_for(&limits, 20);
+        let (base, _, _) = budget_for(&clock(60000), 20);
+        assert!(optimum > base && optimum >= minimum);
+        assert!(maximum < 60000 + 10000 - MOVE_OVERHEAD);
+
+        // In byo-yomi most of the period is used, but never all of it
+        limits.time = [0; 2];
+        let (optimum, maximum, minimum) = budget_for(&limits, 80);
+        assert!(maximum <= 10000 - MOVE_OVERHEAD);
+        assert!(minimum > 10000 * 9 / 10 && optimum >= minimum);
+    }
+
+    #[test]
+    fn canadian_overtime() {
+        let mut limits = clock(60000);
+        limits.byoyomi = [300000; 2];
+        limits.period_moves = 20;
+        let (optimum, maximum, _) = budget_for(&limits, 20);
+        let (base, _, _) = budget_for(&clock(60000), 20);
+        assert!(optimum > base && maximum < 60000);
+
+        // 5 moves left in the period with 100 s left
+        limits.time = [0; 2];
+        limits.byoyomi = [100000; 2];
+        limits.movestogo = 5;
+        let (optimum, maximum, _) = budget_for(&limits, 80);
+        assert!(optimum > 100000 / 10 && optimum < 100000 / 2);
+        assert!(maximum < 100000);
+
+        // Last move of the period
+        limits.movestogo = 1;
+        limits.byoyomi = [5000; 2];
+        let (optimum, maximum, _) = budget_for(&limits, 80);
+        assert!(maximum <= 5000 - MOVE_OVERHEAD && optimum > 5000 / 2);
+    }
+
+    #[test]
+    fn node_clocks_per_side() {
+        // With nodestime 1000 both sides start from their own clock and are
+        // charged only for their own searches
+        let mut available = [None; 2];
+        let mut limits = clock(0);
+        limits.time = [60000, 30000];
+        limits.inc = [1000; 2];
+
+        node_clock(&mut available, &mut limits, WHITE, 1000);
+        charge_nodes(&mut available, &limits, WHITE, 1000, 5000000);
+        assert_eq!(available, [Some(56000000), None]);
+
+        node_clock(&mut available, &mut limits, BLACK, 1000);
+        charge_nodes(&mut available, &limits, BLACK, 1000, 1000000);
+        assert_eq!(available, [Some(56000000), Some(30000000)]);
+
+        // The GUI's clock is ignored once a side has a budget
+        limits.time = [1000, 1000];
+        node_clock(&mut available, &mut limits, WHITE, 1000);
+        assert_eq!(limits.time[WHITE.0 as usize], 56000);
+        assert_eq!(limits.time[BLACK.0 as usize], 1000);
     }
 }
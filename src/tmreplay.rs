@@ -0,0 +1,190 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use cli;
+use json;
+use search;
+use search::DelayType;
+use timeman;
+use types::*;
+use ucioption;
+
+use std;
+use std::fs::File;
+use std::io::BufRead;
+
+// tmreplay replays a log written with the "TimeLog" option using the time
+// management parameters currently set, i.e. "Move Overhead", "Minimum
+// Thinking Time" and "Slow Mover" as given with --option. For every move the
+// budget is calculated again and the logged iterations are checked for the
+// first one after which the search would have stopped. The log only holds
+// the iterations that were actually searched, so a search that would have
+// run longer is reported as using at least the time actually used (marked
+// with '+').
+
+struct Replay {
+    used: i64,
+    optimum: i64,
+    maximum: i64,
+    truncated: bool,
+}
+
+// limits() rebuilds the limits of a logged move
+
+fn limits(record: &json::Value) -> Option<search::LimitsType> {
+    let pair = |key: &str| -> Option<[i64; 2]> {
+        let a = record.get(key)?.as_array()?;
+        Some([a.first()?.as_f64()? as i64, a.get(1)?.as_f64()? as i64])
+    };
+
+    let mut limits = search::LimitsType::new();
+    limits.time = pair("time")?;
+    limits.inc = pair("inc")?;
+    limits.delay = pair("delay")?;
+    limits.byoyomi = pair("byoyomi")?;
+    limits.movestogo = record.get("movestogo")?.as_f64()? as i32;
+    limits.period_moves = record.get("period_moves")?.as_f64()? as i32;
+    limits.delay_type = match record.get("delay_type")?.as_str()? {
+        "bronstein" => DelayType::Bronstein,
+        _ => DelayType::Simple,
+    };
+    Some(limits)
+}
+
+// available() returns the time on the clock for the current move, including
+// time that comes for free with a delay or an overtime period.
+
+fn available(limits: &search::LimitsType, us: Color) -> i64 {
+    let us = us.0 as usize;
+    let mut time = limits.time[us];
+    if limits.delay_type == DelayType::Simple {
+        time += limits.delay[us];
+    }
+    if limits.period_moves == 0 || limits.time[us] == 0 {
+        time += limits.byoyomi[us];
+    }
+    time
+}
+
+fn replay(
+    record: &json::Value, limits: &search::LimitsType, us: Color,
+    params: (i64, i64, i64)
+) -> Option<Replay> {
+    let (min_think_time, move_overhead, slow_mover) = params;
+    let ply = record.get("ply")?.as_f64()? as i32;
+    let ponder = record.get("ponder")?.as_bool()?;
+    let used = record.get("used")?.as_f64()? as i64;
+
+    // Iterations are timed from the start of pondering, but only the time
+    // after "ponderhit" is taken from the clock.
+    let pondered = record.get("pondered").and_then(|p| p.as_f64())
+        .unwrap_or(0.) as i64;
+
+    let (optimum, maximum, minimum) = timeman::budget(limits, us, ply,
+        min_think_time, move_overhead, slow_mover, ponder);
+
+    for it in record.get("iterations")?.as_array()? {
+        let elapsed = it.get("elapsed")?.as_f64()? as i64;
+        let stop_factor = it.get("stop_factor")?.as_f64()?;
+        let clock = std::cmp::max(elapsed - pondered, 0);
+
+        if clock > maximum {
+            break;
+        }
+        if elapsed as f64 > optimum as f64 * stop_factor && clock >= minimum {
+            return Some(Replay {
+                used: clock,
+                optimum,
+                maximum,
+                truncated: false,
+            });
+        }
+    }
+
+    // No logged iteration stops the search, so it would have run until at
+    // least the time actually used, but never beyond the maximum.
+    Some(Replay {
+        used: std::cmp::min(used, maximum),
+        optimum,
+        maximum,
+        truncated: used < maximum,
+    })
+}
+
+// run() replays the given log and prints the logged and the replayed time
+// usage for every move. It returns the exit code of the program.
+
+pub fn run(file: &str) -> i32 {
+    let file = match File::open(file) {
+        Ok(f) => f,
+        Err(_) => {
+            eprintln!("Unable to open file {}", file);
+            return cli::EXIT_FAILURE;
+        }
+    };
+
+    let params = (
+        ucioption::get_i32("Minimum Thinking Time") as i64,
+        ucioption::get_i32("Move Overhead") as i64,
+        ucioption::get_i32("Slow Mover") as i64,
+    );
+
+    println!("{:>5} {:>4} {:>9} | {:>8} {:>8} {:>8} | {:>8} {:>8} {:>9}",
+        "ply", "side", "clock", "optimum", "maximum", "used",
+        "optimum", "maximum", "replayed");
+
+    let mut total = (0, 0);
+    let mut flagged = (0, 0);
+
+    for (n, line) in std::io::BufReader::new(file).lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return cli::EXIT_FAILURE,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record = json::parse(&line);
+        let parsed = record.as_ref().and_then(|record| {
+            let limits = limits(record)?;
+            let us = if record.get("side")?.as_str()? == "w" { WHITE }
+                else { BLACK };
+            let logged = (
+                record.get("optimum")?.as_f64()? as i64,
+                record.get("maximum")?.as_f64()? as i64,
+                record.get("used")?.as_f64()? as i64,
+            );
+            let r = replay(record, &limits, us, params)?;
+            Some((record.get("ply")?.as_f64()? as i32, us, limits, logged, r))
+        });
+
+        let (ply, us, limits, logged, r) = match parsed {
+            Some(p) => p,
+            None => {
+                eprintln!("Invalid time log record on line {}", n + 1);
+                return cli::EXIT_FAILURE;
+            }
+        };
+
+        let clock = available(&limits, us);
+        total.0 += logged.2;
+        total.1 += r.used;
+        if logged.2 >= clock {
+            flagged.0 += 1;
+        }
+        if r.used >= clock {
+            flagged.1 += 1;
+        }
+
+        println!("{:>5} {:>4} {:>9} | {:>8} {:>8} {:>8} | {:>8} {:>8} {:>8}{}",
+            ply, if us == WHITE { "w" } else { "b" }, clock,
+            logged.0, logged.1, logged.2, r.optimum, r.maximum, r.used,
+            if r.truncated { "+" } else { " " });
+    }
+
+    println!("\nTotal time used: {} logged, {} replayed", total.0, total.1);
+    println!("Moves out of time: {} logged, {} replayed", flagged.0,
+        flagged.1);
+
+    cli::EXIT_OK
+}
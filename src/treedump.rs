@@ -0,0 +1,385 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use cli;
+use json;
+use position::Position;
+use types::*;
+use uci;
+
+use std;
+use std::fs::File;
+use std::io::BufRead;
+
+#[cfg(feature = "treedump")]
+use ucioption;
+#[cfg(feature = "treedump")]
+use std::io::{BufWriter, Write};
+#[cfg(feature = "treedump")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "treedump")]
+use std::sync::Mutex;
+
+// The tree dump records the tree searched by the main thread to the file
+// named by the "Tree Dump" option, for finding out why a search returned
+// what it did. Recording is compiled in only with the "treedump" cargo
+// feature. Without it the functions below do nothing and the options do not
+// exist. Every line of the dump is a JSON object of one of these types:
+//
+// search  start of a search, with the root position
+// enter   a node is entered: ply, move leading to it, window and depth
+// eval    static evaluation of the current node
+// note    pruning, reduction or extension decision at the current node
+// exit    the current node returns its value
+//
+// Only nodes up to "Tree Dump Plies" plies from the root are recorded, and
+// with "Tree Dump Root Move" only the subtree of that root move. Helper
+// threads are never recorded, so the tree is complete only with one thread.
+
+#[cfg(feature = "treedump")]
+struct Dump {
+    out: BufWriter<File>,
+    max_ply: i32,
+    root_move: Option<String>,
+    chess960: bool,
+    skip: bool, // The current root move is not recorded
+}
+
+#[cfg(feature = "treedump")]
+static DUMP: Mutex<Option<Dump>> = Mutex::new(None);
+
+// Saves locking DUMP at every node when nothing is recorded
+#[cfg(feature = "treedump")]
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "treedump")]
+impl Dump {
+    fn recorded(&self, ply: i32) -> bool {
+        ply <= self.max_ply && (ply == 0 || !self.skip)
+    }
+
+    fn write(&mut self, obj: json::Object) {
+        let _ = writeln!(self.out, "{}", obj.end());
+    }
+}
+
+// with_dump() runs f on the dump if the main thread records the node at the
+// given ply.
+
+#[cfg(feature = "treedump")]
+fn with_dump<F: FnOnce(&mut Dump)>(pos: &Position, ply: i32, f: F) {
+    if !pos.is_main || !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Some(ref mut dump) = *DUMP.lock().unwrap() {
+        if dump.recorded(ply) {
+            f(dump);
+        }
+    }
+}
+
+// start() opens the dump file for a new search if the "Tree Dump" option is
+// set. The file is overwritten.
+
+#[cfg(feature = "treedump")]
+pub fn start(pos: &Position) {
+    let file = ucioption::get_string("Tree Dump");
+    if file.is_empty() || file == "<empty>" {
+        return;
+    }
+
+    let out = match File::create(&file) {
+        Ok(f) => BufWriter::new(f),
+        Err(_) => {
+            println!("info string Unable to write tree dump {}", file);
+            return;
+        }
+    };
+
+    let root_move = ucioption::get_string("Tree Dump Root Move");
+    let mut dump = Dump {
+        out,
+        max_ply: ucioption::get_i32("Tree Dump Plies"),
+        root_move: if root_move.is_empty() || root_move == "<empty>" { None }
+            else { Some(root_move) },
+        chess960: pos.is_chess960(),
+        skip: false,
+    };
+    dump.write(json::Object::new("search").str("fen", &pos.fen()));
+
+    *DUMP.lock().unwrap() = Some(dump);
+    ACTIVE.store(true, Ordering::Relaxed);
+}
+
+#[cfg(not(feature = "treedump"))]
+pub fn start(_pos: &Position) {}
+
+// finish() closes the dump file at the end of the search
+
+#[cfg(feature = "treedump")]
+pub fn finish() {
+    ACTIVE.store(false, Ordering::Relaxed);
+    if let Some(mut dump) = DUMP.lock().unwrap().take() {
+        let _ = dump.out.flush();
+    }
+}
+
+#[cfg(not(feature = "treedump"))]
+pub fn finish() {}
+
+#[cfg(feature = "treedump")]
+pub fn enter(
+    pos: &Position, ply: i32, m: Move, alpha: Value, beta: Value, depth: Depth
+) {
+    if !pos.is_main || !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    if ply == 1 {
+        if let Some(ref mut dump) = *DUMP.lock().unwrap() {
+            let chess960 = dump.chess960;
+            dump.skip = dump.root_move.as_ref()
+                .is_some_and(|rm| *rm != uci::move_str(m, chess960));
+        }
+    }
+    with_dump(pos, ply, |dump| {
+        let obj = json::Object::new("enter").num("ply", ply)
+            .str("move", &uci::move_str(m, dump.chess960))
+            .num("alpha", alpha.0).num("beta", beta.0)
+            .num("depth", depth / ONE_PLY);
+        dump.write(obj);
+    });
+}
+
+#[cfg(feature = "treedump")]
+pub fn eval(pos: &Position, ply: i32, v: Value) {
+    with_dump(pos, ply, |dump| {
+        dump.write(json::Object::new("eval").num("value", v.0));
+    });
+}
+
+#[cfg(not(feature = "treedump"))]
+#[inline(always)]
+pub fn eval(_pos: &Position, _ply: i32, _v: Value) {}
+
+// note() records a decision taken at the current node, optionally for a
+// move and with a number such as the reduction in plies.
+
+#[cfg(feature = "treedump")]
+pub fn note(pos: &Position, ply: i32, what: &str, m: Move, arg: Option<i32>) {
+    with_dump(pos, ply, |dump| {
+        let m = if m == Move::NONE { None }
+            else { Some(uci::move_str(m, dump.chess960)) };
+
+        // At the root only decisions about the chosen root move are kept
+        if ply == 0 && dump.root_move.is_some() && m.is_some()
+            && dump.root_move != m
+        {
+            return;
+        }
+
+        let mut obj = json::Object::new("note").str("what", what);
+        if let Some(ref m) = m {
+            obj = obj.str("move", m);
+        }
+        if let Some(arg) = arg {
+            obj = obj.num("arg", arg);
+        }
+        dump.write(obj);
+    });
+}
+
+#[cfg(not(feature = "treedump"))]
+#[inline(always)]
+pub fn note(
+    _pos: &Position, _ply: i32, _what: &str, _m: Move, _arg: Option<i32>
+) {}
+
+#[cfg(feature = "treedump")]
+pub fn exit(pos: &Position, ply: i32, v: Value) {
+    with_dump(pos, ply, |dump| {
+        dump.write(json::Object::new("exit").num("value", v.0));
+    });
+}
+
+// The printer reads a dump back into a tree. The notes of a node are kept in
+// order among its children, because a note such as "lmr" refers to the
+// child searched next.
+
+enum Item {
+    Node(Node),
+    Note(json::Value),
+}
+
+struct Node {
+    record: json::Value,
+    eval: Option<i32>,
+    value: Option<i32>,
+    items: Vec<Item>,
+}
+
+fn field(record: &json::Value, key: &str) -> Option<i32> {
+    record.get(key).and_then(|v| v.as_f64()).map(|v| v as i32)
+}
+
+// value_str() formats a value of the dump for the indented tree. Values are
+// kept in internal units, as used by the margins in the search, except for
+// mate scores.
+
+fn value_str(v: i32) -> String {
+    if v == Value::NONE.0 {
+        String::from("none")
+    } else if v >= Value::INFINITE.0 {
+        String::from("inf")
+    } else if v <= -Value::INFINITE.0 {
+        String::from("-inf")
+    } else if v.abs() >= Value::MATE_IN_MAX_PLY.0 {
+        uci::value(Value(v))
+    } else {
+        v.to_string()
+    }
+}
+
+fn print_text(node: &Node, indent: usize) {
+    let r = &node.record;
+    let m = r.get("move").and_then(|m| m.as_str()).unwrap_or("?");
+    let mut s = format!("{:indent$}{} ply {} depth {} window [{}, {}]", "",
+        if m == "(none)" { "root" } else { m },
+        field(r, "ply").unwrap_or(0), field(r, "depth").unwrap_or(0),
+        value_str(field(r, "alpha").unwrap_or(0)),
+        value_str(field(r, "beta").unwrap_or(0)), indent = indent);
+    if let Some(e) = node.eval {
+        s.push_str(&format!(" eval {}", value_str(e)));
+    }
+    s.push_str(&format!(" -> {}",
+        node.value.map_or(String::from("?"), value_str)));
+    println!("{}", s);
+
+    for item in node.items.iter() {
+        match *item {
+            Item::Node(ref child) => print_text(child, indent + 2),
+            Item::Note(ref note) => {
+                let mut s = format!("{:indent$}- {}", "",
+                    note.get("what").and_then(|w| w.as_str()).unwrap_or("?"),
+                    indent = indent + 2);
+                if let Some(m) = note.get("move").and_then(|m| m.as_str()) {
+                    s.push_str(&format!(" {}", m));
+                }
+                if let Some(arg) = field(note, "arg") {
+                    s.push_str(&format!(" {}", arg));
+                }
+                println!("{}", s);
+            }
+        }
+    }
+}
+
+fn node_json(node: &Node) -> String {
+    let r = &node.record;
+    let mut obj = json::Object::new("node")
+        .str("move", r.get("move").and_then(|m| m.as_str()).unwrap_or("?"));
+    for key in ["ply", "depth", "alpha", "beta"].iter() {
+        obj = obj.num(key, field(r, key).unwrap_or(0));
+    }
+    if let Some(e) = node.eval {
+        obj = obj.num("eval", e);
+    }
+    if let Some(v) = node.value {
+        obj = obj.num("value", v);
+    }
+
+    let items: Vec<String> = node.items.iter().map(|item| match *item {
+        Item::Node(ref child) => node_json(child),
+        Item::Note(ref note) => {
+            let mut obj = json::Object::new("note").str("what",
+                note.get("what").and_then(|w| w.as_str()).unwrap_or("?"));
+            if let Some(m) = note.get("move").and_then(|m| m.as_str()) {
+                obj = obj.str("move", m);
+            }
+            if let Some(arg) = field(note, "arg") {
+                obj = obj.num("arg", arg);
+            }
+            obj.end()
+        }
+    }).collect();
+    obj.raw("children", &format!("[{}]", items.join(","))).end()
+}
+
+fn print_root(node: &Node) {
+    if uci::json() {
+        println!("{}", node_json(node));
+    } else {
+        print_text(node, 0);
+    }
+}
+
+// print() is run by the "treeprint" command. It prints every root search of
+// a dump as an indented tree, or as one JSON object per root search with
+// nested children when JSON output is selected. It returns the exit code of
+// the program.
+
+pub fn print(file: &str) -> i32 {
+    let file = match File::open(file) {
+        Ok(f) => f,
+        Err(_) => {
+            eprintln!("Unable to open file {}", file);
+            return cli::EXIT_FAILURE;
+        }
+    };
+
+    let mut stack: Vec<Node> = Vec::new();
+
+    for (n, line) in std::io::BufReader::new(file).lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return cli::EXIT_FAILURE,
+        };
+        let record = match json::parse(&line) {
+            Some(r) => r,
+            None => {
+                eprintln!("Invalid tree dump record on line {}", n + 1);
+                return cli::EXIT_FAILURE;
+            }
+        };
+
+        match record.get("type").and_then(|t| t.as_str()) {
+            Some("search") => {
+                let fen = record.get("fen").and_then(|f| f.as_str());
+                if uci::json() {
+                    println!("{}", json::Object::new("search")
+                        .str("fen", fen.unwrap_or("")).end());
+                } else {
+                    println!("search fen {}", fen.unwrap_or(""));
+                }
+            }
+            Some("enter") => stack.push(Node {
+                record,
+                eval: None,
+                value: None,
+                items: Vec::new(),
+            }),
+            Some("eval") => if let Some(node) = stack.last_mut() {
+                node.eval = field(&record, "value");
+            },
+            Some("note") => if let Some(node) = stack.last_mut() {
+                node.items.push(Item::Note(record));
+            },
+            Some("exit") => if let Some(mut node) = stack.pop() {
+                node.value = field(&record, "value");
+                match stack.last_mut() {
+                    Some(parent) => parent.items.push(Item::Node(node)),
+                    None => print_root(&node),
+                }
+            },
+            _ => {}
+        }
+    }
+
+    // A dump cut short leaves nodes without a value
+    while let Some(node) = stack.pop() {
+        match stack.last_mut() {
+            Some(parent) => parent.items.push(Item::Node(node)),
+            None => print_root(&node),
+        }
+    }
+
+    cli::EXIT_OK
+}
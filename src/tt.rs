@@ -3,6 +3,8 @@
 use types::*;
 
 use std;
+use std::alloc;
+use std::alloc::Layout;
 
 // TTEntry struct is the 10 bytes transposition-table entry, defined as below:
 //
@@ -87,12 +89,23 @@ struct Cluster {
 
 static mut CLUSTER_COUNT: usize = 0;
 static mut TABLE: *mut Cluster = 0 as *mut Cluster;
-static mut TABLE_CAP: usize = 0;
+static mut ALLOCATION: Allocation = Allocation::NoTable;
+static mut LARGE_PAGES_REQUESTED: bool = false;
 static mut GENERATION8: u8 = 0;
 
+// How the memory of the table was obtained, needed to release it again
+enum Allocation {
+    NoTable,
+    Heap(Layout),
+    Mapped(*mut u8, usize),
+}
+
+const LARGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
 pub fn new_search() {
     unsafe {
-        GENERATION8 += 4; // Lower two bits are used by bound
+        // Lower two bits are used by bound, the generation wraps around
+        GENERATION8 = GENERATION8.wrapping_add(4);
     }
 }
 
@@ -114,58 +127,157 @@ fn cluster(key: Key) -> &'static mut Cluster {
 // tt::resize() sets the size of the transposition table, measured in
 // megabytes. The transposition table consists of a power of 2 number of
 // clusters and each cluster consists of CLUSTER_SIZE number of TTEntry.
+// If large pages are requested, the table is allocated 2 MiB-aligned and
+// the OS is asked to back it with transparent huge pages. The new table
+// is zeroed.
 
-pub fn resize(mb_size: usize) {
+pub fn resize(mb_size: usize, large_pages: bool) {
     let new_cluster_count =
         mb_size * 1024 * 1024 / std::mem::size_of::<Cluster>();
 
     unsafe {
-        if new_cluster_count == CLUSTER_COUNT {
+        if new_cluster_count == CLUSTER_COUNT
+            && large_pages == LARGE_PAGES_REQUESTED
+        {
             return;
         }
 
         free();
 
         CLUSTER_COUNT = new_cluster_count;
+        LARGE_PAGES_REQUESTED = large_pages;
+
+        let size = new_cluster_count * std::mem::size_of::<Cluster>();
+
+        if large_pages {
+            let mut huge_mb = 0;
+            if let Some((base, len, table)) = alloc_large_pages(size) {
+                TABLE = table as *mut Cluster;
+                ALLOCATION = Allocation::Mapped(base, len);
+                // Fault the pages in, so that the kernel backs them now
+                std::ptr::write_bytes(table, 0, size);
+                huge_mb = huge_pages_mb(table);
+            }
+            println!("info string Hash table: {} MB, {} MB in large pages",
+                mb_size, huge_mb);
+        }
 
-        let mut v: Vec<Cluster> = Vec::with_capacity(new_cluster_count);
-        TABLE = v.as_mut_ptr();
-        TABLE_CAP = v.capacity();
-        // forget in order to prevent deallocation by dropping
-        std::mem::forget(v);
+        if TABLE.is_null() {
+            let layout = Layout::array::<Cluster>(new_cluster_count).unwrap();
+            TABLE = alloc::alloc_zeroed(layout) as *mut Cluster;
+            if TABLE.is_null() {
+                alloc::handle_alloc_error(layout);
+            }
+            ALLOCATION = Allocation::Heap(layout);
+        }
     }
 }
 
-// tt::free() deallocates the transposition table.
+// alloc_large_pages() maps a zeroed, 2 MiB-aligned block of at least 'size'
+// bytes and advises the kernel to use huge pages for it. It returns the
+// start and length of the mapping and the aligned table address.
+
+#[cfg(target_os = "linux")]
+fn alloc_large_pages(size: usize) -> Option<(*mut u8, usize, *mut u8)> {
+    use libc;
+
+    let size = size.div_ceil(LARGE_PAGE_SIZE) * LARGE_PAGE_SIZE;
+    let len = size + LARGE_PAGE_SIZE;
 
-pub fn free() {
     unsafe {
-        if !TABLE.is_null() {
-            let _ = Vec::from_raw_parts(TABLE, 0, TABLE_CAP);
-            // deallocate by dropping
+        let base = libc::mmap(std::ptr::null_mut(), len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0);
+        if base == libc::MAP_FAILED {
+            return None;
+        }
+        let base = base as *mut u8;
+        let offset = (LARGE_PAGE_SIZE - base as usize % LARGE_PAGE_SIZE)
+            % LARGE_PAGE_SIZE;
+        let table = base.add(offset);
+        if libc::madvise(table as *mut libc::c_void, size,
+            libc::MADV_HUGEPAGE) != 0
+        {
+            libc::munmap(base as *mut libc::c_void, len);
+            return None;
         }
+        Some((base, len, table))
     }
 }
 
-// tt::clear() clears the entire transposition table. It is called whenever
-// the table is resized or when the user asks the program to clear the table
-// (via the UCI interface).
+#[cfg(not(target_os = "linux"))]
+fn alloc_large_pages(_size: usize) -> Option<(*mut u8, usize, *mut u8)> {
+    None
+}
 
-pub fn clear() {
-    let tt_slice = unsafe {
-        std::slice::from_raw_parts_mut(TABLE, CLUSTER_COUNT)
+// huge_pages_mb() returns how many megabytes of the mapping that contains
+// 'addr' the kernel has actually backed with transparent huge pages, as
+// reported by the AnonHugePages field of /proc/self/smaps.
+
+fn huge_pages_mb(addr: *mut u8) -> usize {
+    let smaps = match std::fs::read_to_string("/proc/self/smaps") {
+        Ok(s) => s,
+        Err(_) => return 0,
     };
+    let addr = addr as usize;
+    let mut inside = false;
+    for line in smaps.lines() {
+        let mut fields = line.split_whitespace();
+        let first = fields.next().unwrap_or("");
+        if !first.ends_with(':') {
+            // A mapping header such as "7f0000000000-7f0000200000 rw-p ..."
+            let mut range = first.split('-')
+                .map(|a| usize::from_str_radix(a, 16).unwrap_or(0));
+            let start = range.next().unwrap_or(0);
+            let end = range.next().unwrap_or(0);
+            inside = start <= addr && addr < end;
+        } else if inside && first == "AnonHugePages:" {
+            let kb: usize = fields.next().and_then(|n| n.parse().ok())
+                .unwrap_or(0);
+            return kb / 1024;
+        }
+    }
+    0
+}
 
-    for cluster in tt_slice.iter_mut() {
-        for tte in cluster.entry.iter_mut() {
-            tte.key16 = 0;
-            tte.move16 = 0;
-            tte.value16 = 0;
-            tte.eval16 = 0;
-            tte.gen_bound8 = 0;
-            tte.depth8 = 0;
-            tte.key16 = 0;
+// tt::free() deallocates the transposition table.
+
+pub fn free() {
+    unsafe {
+        match ALLOCATION {
+            Allocation::NoTable => {}
+            Allocation::Heap(layout) =>
+                alloc::dealloc(TABLE as *mut u8, layout),
+            Allocation::Mapped(base, len) => unmap(base, len),
         }
+        ALLOCATION = Allocation::NoTable;
+        TABLE = std::ptr::null_mut();
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn unmap(base: *mut u8, len: usize) {
+    unsafe {
+        ::libc::munmap(base as *mut ::libc::c_void, len);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unmap(_base: *mut u8, _len: usize) {}
+
+// tt::clear_part() clears the part 'idx' out of 'count' equal parts of the
+// transposition table. Each search thread clears its own part, so that the
+// entire table is cleared in parallel whenever the user asks the program to
+// clear the table (via the UCI interface).
+
+pub fn clear_part(idx: usize, count: usize) {
+    if count == 0 {
+        return;
+    }
+    unsafe {
+        let start = CLUSTER_COUNT * idx / count;
+        let end = CLUSTER_COUNT * (idx + 1) / count;
+        std::ptr::write_bytes(TABLE.add(start), 0, end - start);
     }
 }
 
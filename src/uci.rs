@@ -1,18 +1,24 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use benchmark::*;
+use cluster;
+use evaluate;
+use json;
 use misc;
 use movegen::*;
+use pns;
 use position::*;
 use search;
+use tb;
 use threads;
 use threads::PosData;
+use timeman;
 use types::*;
 use ucioption;
 
 use std;
-use std::env;
 use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Instant;
 
 // FEN string of the initial position, normal chess
@@ -22,7 +28,8 @@ const START_FEN: &'static str =
 // position() is called when engine receives the "position" UCI command.
 // The function sets up the position described in the given FEN string ("fen")
 // or the starting position ("startpos") and then makes the moves given in the
-// following move list ("moves").
+// following move list ("moves"). An invalid FEN or an illegal move is
+// reported as a failure, keeping the moves made before it.
 
 fn position(pos: &mut Position, pos_data: &mut PosData, args: &str) {
     let fen: &str;
@@ -32,11 +39,16 @@ fn position(pos: &mut Position, pos_data: &mut PosData, args: &str) {
         None => args.len(),
     };
 
-    if &args[0..8] == "startpos" {
+    if args.starts_with("startpos") {
         fen = START_FEN;
-    } else if &args[0..3] == "fen" {
+    } else if args.starts_with("fen") {
         fen = (&args[3..moves]).trim();
+        if !valid_fen(fen) {
+            fail(&format!("Invalid FEN: {}", fen));
+            return;
+        }
     } else {
+        fail(&format!("Invalid position: {}", args));
         return;
     }
 
@@ -55,6 +67,7 @@ fn position(pos: &mut Position, pos_data: &mut PosData, args: &str) {
     for token in iter {
         let m = to_move(pos, token);
         if m == Move::NONE {
+            fail(&format!("Illegal move: {}", token));
             break;
         }
         let gives_check = pos.gives_check(m);
@@ -63,19 +76,59 @@ fn position(pos: &mut Position, pos_data: &mut PosData, args: &str) {
     }
 }
 
+// valid_fen() checks the fields of a FEN string that Position::set()
+// relies on: eight ranks of eight squares with one king of each colour, the
+// side to move, the castling and en passant fields and numeric counters.
+
+fn valid_fen(fen: &str) -> bool {
+    let fields: Vec<&str> = fen.split_whitespace().collect();
+    if fields.len() < 4 || fields.len() > 6 {
+        return false;
+    }
+
+    let ranks: Vec<&str> = fields[0].split('/').collect();
+    let squares_ok = ranks.len() == 8 && ranks.iter().all(|r| {
+        let mut n = 0;
+        for c in r.chars() {
+            n += match c.to_digit(10) {
+                Some(d) if (1..=8).contains(&d) => d,
+                None if "PNBRQKpnbrqk".contains(c) => 1,
+                _ => return false,
+            };
+        }
+        n == 8
+    });
+    let kings = |k| fields[0].chars().filter(|&c| c == k).count() == 1;
+
+    let ep: Vec<char> = fields[3].chars().collect();
+    squares_ok && kings('K') && kings('k')
+        && (fields[1] == "w" || fields[1] == "b")
+        && (fields[2] == "-" || fields[2].chars().all(|c|
+            "KQkqABCDEFGHabcdefgh".contains(c)))
+        && (fields[3] == "-" || (ep.len() == 2
+            && "abcdefgh".contains(ep[0]) && "36".contains(ep[1])))
+        && fields[4..].iter().all(|f| f.parse::<u32>().is_ok())
+}
+
 // setoption() is called when engine received the "setoption" UCI command.
 // The function updates the UCI option ("name") to the given value ("value").
 
 fn setoption(args: &str) {
-    let idx = args.find("name").unwrap();
+    let idx = match args.find("name") {
+        Some(idx) => idx,
+        None => return fail(&format!("Invalid setoption: {}", args)),
+    };
     let args = &args[idx+4..];
-    if let Some(idx) = args.find("value") {
+    let ok = if let Some(idx) = args.find("value") {
         let name = &args[..idx].trim();
         let value = &args[idx+5..].trim();
-        ucioption::set(name, value);
+        ucioption::set(name, value)
     } else {
         let name = args.trim();
-        ucioption::set(name, &"");
+        ucioption::set(name, &"")
+    };
+    if !ok {
+        FAILED.store(true, Ordering::Relaxed);
     }
 }
 
@@ -86,14 +139,23 @@ fn setoption(args: &str) {
 fn go(pos: &mut Position, pos_data: &Arc<RwLock<PosData>>, args: &str) {
     let mut limits = search::LimitsType::new();
     let mut searchmoves: Vec<Move> = Vec::new();
+    let mut excludemoves: Vec<Move> = Vec::new();
     let mut ponder_mode = false;
+    let mut reserve: Option<[i64; 2]> = None;
 
-    let mut iter = args.split_whitespace();
+    let mut iter = args.split_whitespace().peekable();
     while let Some(token) = iter.next() {
         match token {
-            "searchmoves" => {
-                while let Some(token) = iter.next() {
-                    searchmoves.push(to_move(pos, token));
+            // A move list runs up to the other move list or to the end
+            "searchmoves" | "excludemoves" => {
+                let list = if token == "searchmoves" { &mut searchmoves }
+                    else { &mut excludemoves };
+                while let Some(&token) = iter.peek() {
+                    if token == "searchmoves" || token == "excludemoves" {
+                        break;
+                    }
+                    list.push(to_move(pos, token));
+                    iter.next();
                 }
             }
             "wtime" => limits.time[WHITE.0 as usize] =
@@ -104,6 +166,28 @@ fn go(pos: &mut Position, pos_data: &Arc<RwLock<PosData>>, args: &str) {
                 iter.next().unwrap().parse().unwrap(),
             "binc" => limits.inc[BLACK.0 as usize] =
                 iter.next().unwrap().parse().unwrap(),
+            "wdelay" => limits.delay[WHITE.0 as usize] =
+                iter.next().unwrap().parse().unwrap(),
+            "bdelay" => limits.delay[BLACK.0 as usize] =
+                iter.next().unwrap().parse().unwrap(),
+            "delaytype" => limits.delay_type = match iter.next() {
+                Some("bronstein") => search::DelayType::Bronstein,
+                _ => search::DelayType::Simple,
+            },
+            "wreserve" => reserve.get_or_insert([0; 2])[WHITE.0 as usize] =
+                iter.next().unwrap().parse().unwrap(),
+            "breserve" => reserve.get_or_insert([0; 2])[BLACK.0 as usize] =
+                iter.next().unwrap().parse().unwrap(),
+            "byoyomi" => limits.byoyomi =
+                [iter.next().unwrap().parse().unwrap(); 2],
+            "wbyoyomi" => limits.byoyomi[WHITE.0 as usize] =
+                iter.next().unwrap().parse().unwrap(),
+            "bbyoyomi" => limits.byoyomi[BLACK.0 as usize] =
+                iter.next().unwrap().parse().unwrap(),
+            "periodmoves" => limits.period_moves =
+                iter.next().unwrap().parse().unwrap(),
+            // Spare periods are never planned with, see timeman::budget()
+            "periods" => { iter.next(); }
             "movestogo" => limits.movestogo =
                 iter.next().unwrap().parse().unwrap(),
             "depth" => limits.depth = iter.next().unwrap().parse().unwrap(),
@@ -118,21 +202,47 @@ fn go(pos: &mut Position, pos_data: &Arc<RwLock<PosData>>, args: &str) {
         }
     }
 
-    threads::start_thinking(pos, pos_data, &limits, searchmoves, ponder_mode);
+    // A fixed time per move with a reserve behaves like a simple delay of
+    // the move time on a clock holding the reserve: the reserve is only
+    // used up by thinking longer than the move time.
+    if let Some(reserve) = reserve {
+        limits.time = reserve;
+        limits.inc = [0; 2];
+        limits.delay = [limits.movetime; 2];
+        limits.delay_type = search::DelayType::Simple;
+        limits.movetime = 0;
+    }
+
+    // In deterministic mode every search starts from the same state and
+    // node limits are counted exactly, so that results are reproducible.
+    if ucioption::get_bool("Deterministic") {
+        limits.exact_nodes = true;
+        search::clear();
+    }
+
+    threads::start_thinking(pos, pos_data, &limits, searchmoves,
+        excludemoves, ponder_mode);
 }
 
 // bench() is called when engine receives the "bench" command. First a list
 // of UCI commands is setup according to bench parameters. Then the commands
-// are run one by one. At the end, a summary is printed.
-
-fn bench(pos: &mut Position, pos_data: &Arc<RwLock<PosData>>, args: &str) {
+// are run one by one. At the end, a summary is printed. For "votebench"
+// ('votes' set) the moves chosen from the threads' results by score and by
+// vote are also compared after each search.
+
+fn bench(
+    pos: &mut Position, pos_data: &Arc<RwLock<PosData>>, args: &str,
+    votes: bool
+) {
     let list = setup_bench(pos, args);
     let num = list.iter().filter(|&s| s.find("go ") != None).count();
+    let json = json();
 
     let now = Instant::now();
 
     let mut cnt = 1;
     let mut nodes = 0;
+    let mut vote_stats = VoteStats::default();
     for cmd in list.iter() {
         let cmd_slice: &str = &cmd;
         let (token, args) =
@@ -143,41 +253,486 @@ fn bench(pos: &mut Position, pos_data: &Arc<RwLock<PosData>>, args: &str) {
             };
         let args = args.trim();
         if token == "go" {
-            eprintln!("\nPosition: {}/{}", cnt, num);
-            cnt += 1;
+            if !json {
+                eprintln!("\nPosition: {}/{}", cnt, num);
+            }
+            let start = Instant::now();
             go(pos, pos_data, args);
             threads::wait_for_main();
-            nodes += threads::nodes_searched();
+            let pos_nodes = threads::nodes_searched();
+            nodes += pos_nodes;
+            if json {
+                println!("{}", json::Object::new("bench")
+                    .num("position", cnt).str("fen", &pos.fen())
+                    .num("nodes", pos_nodes).num("time", elapsed_ms(start))
+                    .str("bestmove",
+                        &move_str(threads::best_move(), pos.is_chess960()))
+                    .end());
+            }
+            if votes {
+                compare_votes(pos, pos_data, cnt, &mut vote_stats);
+            }
+            cnt += 1;
         } else if token == "setoption" {
             setoption(args);
         } else if token == "position" {
             position(pos, &mut pos_data.write().unwrap(), args);
         } else if token == "ucinewgame" {
             search::clear();
+            timeman::new_game();
         }
     }
 
-    let duration = now.elapsed();
-    let elapsed = (duration.as_secs() as u64) * 1000
-        + (duration.subsec_nanos() as u64) / 10000000 + 1;
+    let elapsed = elapsed_ms(now) + 1;
+
+    if votes {
+        vote_stats.print();
+    }
+
+    if json {
+        println!("{}", json::Object::new("bench_summary").num("time", elapsed)
+            .num("nodes", nodes).num("nps", 1000 * nodes / elapsed).end());
+        return;
+    }
 
+    // With the default parameters the total node count is the bench
+    // "signature": any functional change to search or eval changes it.
     eprintln!("\n===========================\
         \nTotal time (ms) : {}\
-        \nNode searched   : {}\
+        \nNodes searched  : {}\
         \nNodes/second    : {}",
         elapsed, nodes, 1000 * nodes / elapsed);
 }
 
-// cmd_loop() waits for a command from stdin, parses it and calls the
-// appropriate function. Also intercepts EOF from stdin to ensure a
-// graceful exit if the GUI dies unexpectedly. When called with some comand
-// line arguments, e.g. to run 'bench', once the command is executed the
-// function returns immediately. In addition to the UCI ones, some additional
-// debug commands are supported.
+// VoteStats sums up the comparison of "votebench": the positions searched
+// with more than one result, those where voting changes the move, and how
+// often the move picked by each rule matches that of the reference search.
+
+#[derive(Default)]
+struct VoteStats {
+    positions: u64,
+    changed: u64,
+    score_matches: u64,
+    vote_matches: u64,
+}
+
+impl VoteStats {
+    fn print(&self) {
+        if json() {
+            println!("{}", json::Object::new("votebench_summary")
+                .num("positions", self.positions)
+                .num("changed", self.changed)
+                .num("score_matches", self.score_matches)
+                .num("vote_matches", self.vote_matches).end());
+        } else {
+            eprintln!("\n===========================\
+                \nCompared positions : {}\
+                \nMoves changed      : {}\
+                \nReference by score : {}\
+                \nReference by vote  : {}",
+                self.positions, self.changed, self.score_matches,
+                self.vote_matches);
+        }
+    }
+}
+
+// compare_votes() picks the best move of the last search from the results
+// of all threads both by score and by vote, and checks both against a
+// reference move: that of a single-threaded search two plies deeper than
+// the deepest thread got. The outcome is added to the statistics.
+
+fn compare_votes(
+    pos: &mut Position, pos_data: &Arc<RwLock<PosData>>, cnt: usize,
+    stats: &mut VoteStats
+) {
+    let results = threads::results();
+    if results.len() < 2 {
+        return;
+    }
+    let by_score = results[threads::pick_by_score(&results)].pv[0];
+    let by_vote = results[threads::pick_by_votes(&results)].pv[0];
+    let depth = results.iter().map(|r| r.depth / ONE_PLY).max().unwrap() + 2;
+
+    // The helper threads sit the reference search out, so that they and
+    // their histories are the same as without it
+    QUIET.store(true, Ordering::Relaxed);
+    threads::set_main_only(true);
+    go(pos, pos_data, &format!("depth {}", depth));
+    threads::wait_for_main();
+    let reference = threads::best_move();
+    threads::set_main_only(false);
+    QUIET.store(false, Ordering::Relaxed);
+
+    stats.positions += 1;
+    stats.changed += (by_score != by_vote) as u64;
+    stats.score_matches += (by_score == reference) as u64;
+    stats.vote_matches += (by_vote == reference) as u64;
+
+    let chess960 = pos.is_chess960();
+    if json() {
+        println!("{}", json::Object::new("votebench").num("position", cnt)
+            .num("threads", results.len())
+            .str("score_move", &move_str(by_score, chess960))
+            .str("vote_move", &move_str(by_vote, chess960))
+            .str("reference_move", &move_str(reference, chess960))
+            .num("reference_depth", depth)
+            .end());
+    } else {
+        eprintln!("Score rule: {}, vote: {}, reference at depth {}: {}",
+            move_str(by_score, chess960), move_str(by_vote, chess960), depth,
+            move_str(reference, chess960));
+    }
+}
+
+// eval() is called when engine receives the "eval" command. It prints the
+// static evaluation of the current position from White's point of view.
+
+fn eval(pos: &Position) {
+    let v = if pos.checkers() != 0 {
+        None
+    } else {
+        // Evaluate without the contempt of the last search
+        let v = evaluate::evaluate_with_contempt(pos, Score::ZERO);
+        Some(if pos.side_to_move() == WHITE { v } else { -v })
+    };
+
+    if json() {
+        let obj = json::Object::new("eval").str("fen", &pos.fen());
+        println!("{}", match v {
+            Some(v) => obj.raw("score", &value_json(v)),
+            None => obj.str("score", "none"),
+        }.end());
+    } else if let Some(v) = v {
+        println!("Total evaluation: {:.2} (white side)",
+            v.0 as f64 / PawnValueEg.0 as f64);
+    } else {
+        println!("Total evaluation: none (in check)");
+    }
+}
+
+// hashstats() is called when engine receives the "hashstats" command. It
+// prints the sizes of the pawn and material hash tables and their hit rates
+// during the last search.
+
+fn hashstats() {
+    threads::wait_for_main();
+
+    let (pawn_entries, material_entries) = threads::table_sizes();
+    let stats = threads::table_stats();
+    let tables = [
+        ("pawns", "Pawn hash    ", pawn_entries, stats.pawns_probes,
+            stats.pawns_hits),
+        ("material", "Material hash", material_entries,
+            stats.material_probes, stats.material_hits),
+    ];
+
+    for &(key, name, entries, probes, hits) in tables.iter() {
+        let rate = if probes == 0 { 0.0 }
+            else { 100.0 * hits as f64 / probes as f64 };
+        let obj = json::Object::new("hashstats").str("table", key)
+            .num("entries", entries).num("probes", probes).num("hits", hits)
+            .num("hitrate", format!("{:.2}", rate));
+        let s = format!("{} : {} entries, {} probes, {} hits ({:.2}%)", name,
+            entries, probes, hits, rate);
+        println!("{}", if json() { obj.end() } else { s });
+    }
+}
+
+// stats() is called when engine receives the "stats" command. It prints
+// the search statistics of the last search, summed over all threads.
+
+#[cfg(feature = "searchstats")]
+fn stats() {
+    threads::wait_for_main();
+
+    let s = threads::search_stats();
+    let rate = |n: u64, total: u64| if total == 0 { 0.0 }
+        else { 100.0 * n as f64 / total as f64 };
+    // Moves searched per node whose move loop was entered. This is not
+    // the effective branching factor, which compares whole iterations.
+    let moves_per_node = if s.expanded_nodes == 0 { 0.0 }
+        else { s.moves_searched as f64 / s.expanded_nodes as f64 };
+    // Geometric mean of the node ratios of consecutive iterations
+    let branching = if s.iterations == 0 { 0.0 }
+        else { (s.log_branching / s.iterations as f64).exp() };
+
+    if json() {
+        println!("{}", json::Object::new("stats")
+            .num("pv_nodes", s.pv_nodes).num("non_pv_nodes", s.non_pv_nodes)
+            .num("qsearch_nodes", s.qsearch_nodes)
+            .num("tt_cutoffs", s.tt_cutoffs)
+            .num("null_move_tries", s.null_move_tries)
+            .num("null_move_cutoffs", s.null_move_cutoffs)
+            .num("futility_prunes", s.futility_prunes)
+            .num("lmr_searches", s.lmr_searches)
+            .num("lmr_researches", s.lmr_researches)
+            .num("probcuts", s.probcuts)
+            .num("singular_extensions", s.singular_extensions)
+            .num("beta_cutoffs", s.beta_cutoffs)
+            .num("first_move_cutoffs", s.first_move_cutoffs)
+            .num("moves_per_node", format!("{:.2}", moves_per_node))
+            .num("branching_factor", format!("{:.2}", branching)).end());
+        return;
+    }
+
+    println!("Nodes            : {} pv, {} non-pv, {} qsearch",
+        s.pv_nodes, s.non_pv_nodes, s.qsearch_nodes);
+    println!("TT cutoffs       : {}", s.tt_cutoffs);
+    println!("Null move        : {} tries, {} cutoffs ({:.2}%)",
+        s.null_move_tries, s.null_move_cutoffs,
+        rate(s.null_move_cutoffs, s.null_move_tries));
+    println!("Futility prunes  : {}", s.futility_prunes);
+    println!("LMR              : {} searches, {} re-searches ({:.2}%)",
+        s.lmr_searches, s.lmr_researches,
+        rate(s.lmr_researches, s.lmr_searches));
+    println!("ProbCut          : {}", s.probcuts);
+    println!("Singular ext.    : {}", s.singular_extensions);
+    println!("Beta cutoffs     : {}, {} at the first move ({:.2}%)",
+        s.beta_cutoffs, s.first_move_cutoffs,
+        rate(s.first_move_cutoffs, s.beta_cutoffs));
+    println!("Moves per node   : {:.2}", moves_per_node);
+    println!("Branching factor : {:.2}", branching);
+}
+
+#[cfg(not(feature = "searchstats"))]
+fn stats() {
+    println!("info string Search statistics need the searchstats feature");
+}
+
+// verify_puzzle() is called when engine receives the "verifypuzzle" command.
+// It checks that the side to move has exactly one winning move at each of its
+// turns along the solution. The solution is given after "moves", otherwise
+// the engine's own best moves are followed. Every position is searched with
+// MultiPV 2: a move is decisive if it mates, wins by the tablebases or scores
+// at least "threshold" centipawns. A second decisive move at the first move
+// is a cook, at a later move a dual. The search limits are given as for "go"
+// ("depth", "movetime" or "nodes") and default to depth 18.
+
+const PUZZLE_MAX_PLIES: usize = 60;
+
+fn verify_puzzle(
+    pos: &mut Position, pos_data: &Arc<RwLock<PosData>>, args: &str
+) {
+    threads::wait_for_main();
+
+    let mut limits: Vec<String> = Vec::new();
+    let mut threshold = 400;
+    let mut solution: Vec<String> = Vec::new();
+
+    let mut iter = args.split_whitespace();
+    while let Some(token) = iter.next() {
+        match token {
+            "depth" | "movetime" | "nodes" => if let Some(v) = iter.next() {
+                limits.push(format!("{} {}", token, v));
+            },
+            "threshold" => threshold = iter.next()
+                .and_then(|v| v.parse().ok()).unwrap_or(threshold),
+            "moves" => solution.extend(iter.by_ref().map(String::from)),
+            _ => {}
+        }
+    }
+
+    let go_args = if limits.is_empty() { String::from("depth 18") }
+        else { limits.join(" ") };
+    let decisive = Value(threshold * PawnValueEg.0 / 100);
+    let chess960 = pos.is_chess960();
+
+    let (fen, played) = {
+        let data = pos_data.read().unwrap();
+        let moves: Vec<String> =
+            data.moves.iter().map(|&m| move_str(m, chess960)).collect();
+        (data.fen.clone(), moves)
+    };
+    let setup = |line: &[String]| {
+        let moves: Vec<String> =
+            played.iter().chain(line.iter()).cloned().collect();
+        format!("fen {} moves {}", fen, moves.join(" "))
+    };
+
+    let solver = pos.side_to_move();
+    let multi_pv = ucioption::get_i32("MultiPV");
+    ucioption::set("MultiPV", "2");
+    QUIET.store(true, Ordering::Relaxed);
+
+    let mut line: Vec<String> = Vec::new();
+    let mut cooks = 0;
+    let mut duals = 0;
+    let mut sound = true;
+    let mut end = "ply limit";
+
+    for ply in 0..PUZZLE_MAX_PLIES {
+        position(pos, &mut pos_data.write().unwrap(), &setup(&line));
+
+        if MoveList::new::<Legal>(pos).len() == 0 {
+            end = if pos.checkers() != 0 { "checkmate" } else { "stalemate" };
+            break;
+        }
+
+        let given = match solution.get(ply) {
+            Some(s) => {
+                let m = to_move(pos, s);
+                if m == Move::NONE {
+                    end = "illegal move in solution";
+                    sound = false;
+                    break;
+                }
+                Some(m)
+            }
+            None if !solution.is_empty() => {
+                end = "end of solution";
+                break;
+            }
+            None => None,
+        };
+
+        go(pos, pos_data, &go_args);
+        threads::wait_for_main();
+
+        let results = threads::root_results();
+        let best = match results.first() {
+            Some(&(m, _)) => m,
+            None => break,
+        };
+        let m = given.unwrap_or(best);
+        let mut score = results.iter().find(|r| r.0 == m).map(|r| r.1);
+        let second = results.iter().find(|r| r.0 != m).cloned();
+
+        // A solution move outside the MultiPV lines is searched on its own
+        if score.is_none() && pos.side_to_move() == solver {
+            go(pos, pos_data, &format!("{} searchmoves {}", go_args,
+                move_str(m, chess960)));
+            threads::wait_for_main();
+            score = threads::root_results().first().map(|r| r.1);
+        }
+
+        let verdict = if pos.side_to_move() != solver {
+            "defence"
+        } else if !matches!(score, Some(v) if v >= decisive) {
+            sound = false;
+            "not winning"
+        } else if second.is_some_and(|r| r.1 >= decisive) {
+            if ply == 0 {
+                cooks += 1;
+                "cook"
+            } else {
+                duals += 1;
+                "dual"
+            }
+        } else {
+            "unique"
+        };
+
+        let side = if pos.side_to_move() == WHITE { "w" } else { "b" };
+        let in_tb = tb::root_in_tb();
+
+        if json() {
+            let mut obj = json::Object::new("puzzle").num("ply", ply + 1)
+                .str("side", side).str("move", &move_str(m, chess960));
+            obj = match score {
+                Some(v) => obj.raw("score", &value_json(v)),
+                None => obj.str("score", "none"),
+            };
+            if let Some((alt, v)) = second {
+                obj = obj.str("second", &move_str(alt, chess960))
+                    .raw("second_score", &value_json(v));
+            }
+            println!("{}", obj.raw("tb", if in_tb { "true" } else { "false" })
+                .str("verdict", verdict).end());
+        } else {
+            println!("ply {} {} {} score {} second {}{} {}",
+                ply + 1, side, move_str(m, chess960),
+                score.map_or(String::from("none"), value),
+                second.map_or(String::from("none"), |(alt, v)|
+                    format!("{} {}", move_str(alt, chess960), value(v))),
+                if in_tb { " tb" } else { "" }, verdict);
+        }
+
+        if verdict == "not winning" {
+            end = verdict;
+            break;
+        }
+
+        line.push(move_str(m, chess960));
+    }
+
+    if json() {
+        println!("{}", json::Object::new("puzzle_summary")
+            .raw("sound", if sound && cooks == 0 { "true" } else { "false" })
+            .num("cooks", cooks).num("duals", duals).str("end", end).end());
+    } else {
+        println!("Puzzle {}: {} cooks, {} duals, ended by {}",
+            if sound && cooks == 0 { "sound" } else { "unsound" },
+            cooks, duals, end);
+    }
+
+    QUIET.store(false, Ordering::Relaxed);
+    ucioption::set("MultiPV", &multi_pv.to_string());
+    position(pos, &mut pos_data.write().unwrap(), &setup(&[]));
+}
+
+// elapsed_ms() returns the number of milliseconds since the given instant
+
+pub fn elapsed_ms(since: Instant) -> u64 {
+    let duration = since.elapsed();
+    duration.as_secs() * 1000 + (duration.subsec_nanos() as u64) / 1000000
+}
+
+// cmd_loop() first runs the given list of commands, e.g. those built from
+// the command line, and then, if 'interactive' is set, waits for commands
+// from stdin, parses them and calls the appropriate function. Also
+// intercepts EOF from stdin to ensure a graceful exit if the GUI dies
+// unexpectedly. In addition to the UCI ones, some additional debug commands
+// are supported. When not interactive, the function returns once the last
+// search has finished. It returns false if any command failed.
+
+pub fn cmd_loop(commands: &[String], interactive: bool) -> bool {
+    let (mut pos, pos_data) = new_position();
+
+    for cmd in commands.iter() {
+        if !execute(&mut pos, &pos_data, cmd) {
+            return !FAILED.load(Ordering::Relaxed);
+        }
+    }
+
+    if !interactive {
+        threads::wait_for_main();
+        return !FAILED.load(Ordering::Relaxed);
+    }
+
+    loop {
+        let mut cmd = String::new();
+        // Block here waiting for input or EOF
+        match std::io::stdin().read_line(&mut cmd) {
+            Ok(0) | Err(_) => cmd = String::from("quit"),
+            Ok(_) => {}
+        }
+        if !execute(&mut pos, &pos_data, &cmd) {
+            break;
+        }
+    }
+
+    !FAILED.load(Ordering::Relaxed)
+}
+
+// serve() runs the commands returned by next() until it returns None or a
+// "quit", as cmd_loop() does with stdin. It is used by cluster workers.
+
+pub fn serve<F: FnMut() -> Option<String>>(mut next: F) {
+    let (mut pos, pos_data) = new_position();
+
+    loop {
+        let cmd = next().unwrap_or_else(|| String::from("quit"));
+        if !execute(&mut pos, &pos_data, &cmd) {
+            break;
+        }
+    }
+}
 
-pub fn cmd_loop() {
+fn new_position() -> (Box<Position>, Arc<RwLock<PosData>>) {
     let mut pos = Box::new(Position::new());
 
+    let (pawn_entries, material_entries) = threads::table_sizes();
+    pos.init_tables(pawn_entries, material_entries);
     pos.init_states();
     pos.set(START_FEN, false);
 
@@ -186,67 +741,135 @@ pub fn cmd_loop() {
         moves: Vec::new(),
     }));
 
-    let mut cmd = String::new();
-    for arg in env::args().skip(1) {
-        cmd.push_str(&arg);
-        cmd.push(' ');
-    }
+    (pos, pos_data)
+}
 
-    loop {
-        if env::args().len() == 1 {
-            cmd = String::new();
-            // Block here waiting for input or EOF
-            if let Err(_) = std::io::stdin().read_line(&mut cmd) {
-                cmd = String::from("quit");
-            }
-        }
-        let cmd_slice = cmd.trim();
-        let (token, args) =
-            if let Some(idx) = cmd_slice.find(char::is_whitespace) {
-                cmd_slice.split_at(idx)
+// execute() runs a single command. It returns false if the engine should
+// quit.
+
+fn execute(
+    pos: &mut Position, pos_data: &Arc<RwLock<PosData>>, cmd: &str
+) -> bool {
+    let cmd_slice = cmd.trim();
+    let (token, args) =
+        if let Some(idx) = cmd_slice.find(char::is_whitespace) {
+            cmd_slice.split_at(idx)
+        } else {
+            (cmd_slice, "")
+        };
+    let args = args.trim();
+
+    // The GUI sends 'ponderhit' to tell us the user has played the
+    // expected move. So 'ponderhit' will be sent if we were told to
+    // ponder on the same move the user has played. We should continue
+    // searching but switch from pondering to normal search. In case
+    // threads::stop_on_ponderhit() is true, we are waiting for
+    // 'ponderhit' to stop the search, for instance if max search depth
+    // has been reached.
+    match token {
+        "quit" | "stop" => threads::set_stop(true),
+        "ponderhit" => {
+            if threads::stop_on_ponderhit() {
+                threads::set_stop(true);
             } else {
-                (cmd_slice, "")
-            };
-        let args = args.trim();
-
-        // The GUI sends 'ponderhit' to tell us the user has played the
-        // expected move. So 'ponderhit' will be sent if we were told to
-        // ponder on the same move the user has played. We should continue
-        // searching but switch from pondering to normal search. In case
-        // threads::stop_on_ponderhit() is true, we are waiting for
-        // 'ponderhit' to stop the search, for instance if max search depth
-        // has been reached.
-        match token {
-            "quit" | "stop" => threads::set_stop(true),
-            "ponderhit" => {
-                if threads::stop_on_ponderhit() {
-                    threads::set_stop(true);
-                } else {
-                    threads::set_ponder(false); // Switch to normal search
-                }
-            }
-            "uci" => {
-                println!("id name {}", misc::engine_info(true));
-                ucioption::print();
-                println!("uciok");
+                timeman::ponderhit();
+                threads::set_ponder(false); // Switch to normal search
             }
-            "setoption" => setoption(args),
-            "go" => go(&mut pos, &pos_data, args),
-            "position" =>
-                position(&mut pos, &mut pos_data.write().unwrap(), args),
-            "ucinewgame" => search::clear(),
-            "isready" => println!("readyok"),
-
-            // Additional custom non-UCI commands
-            "bench" => bench(&mut pos, &pos_data, args),
-            "d" => pos.print(),
-            _ => println!("Unknown command: {} {}", cmd, args)
         }
-        if env::args().len() > 1 || token == "quit" {
-            // Command-line args are one-shot
-            break;
+        "uci" => {
+            println!("id name {}", misc::engine_info(true));
+            ucioption::print();
+            println!("uciok");
         }
+        "setoption" => setoption(args),
+        "go" => go(pos, pos_data, args),
+        "position" => position(pos, &mut pos_data.write().unwrap(), args),
+        "ucinewgame" => {
+            search::clear();
+            timeman::new_game();
+            cluster::new_game();
+        }
+        "isready" => println!("readyok"),
+
+        // Additional custom non-UCI commands
+        "bench" => bench(pos, pos_data, args, false),
+        "votebench" => bench(pos, pos_data, args, true),
+        "d" => pos.print(),
+        "eval" => eval(pos),
+        "hashstats" => hashstats(),
+        "stats" => stats(),
+        "verifypuzzle" => verify_puzzle(pos, pos_data, args),
+        "pns" => {
+            threads::wait_for_main();
+            pns::run(pos, args);
+        }
+        "" => {}
+        _ => fail(&format!("Unknown command: {}", cmd_slice)),
     }
+
+    token != "quit"
+}
+
+// json() returns true if output is to be formatted as JSON lines rather
+// than as plain UCI text.
+
+pub fn json() -> bool {
+    ucioption::get_string("Output Format") == "json"
+}
+
+// A command that fails, e.g. on an invalid FEN or an unknown command, is
+// remembered so that cmd_loop() can report it in the exit code
+static FAILED: AtomicBool = AtomicBool::new(false);
+
+// fail() reports the failure of a command.
+
+fn fail(msg: &str) {
+    println!("info string {}", msg);
+    FAILED.store(true, Ordering::Relaxed);
+}
+
+// Commands that run searches of their own, such as "verifypuzzle", silence
+// the search output to keep their report readable
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+// value_json() converts a Value to a JSON object with either a "cp" or a
+// "mate" field, following the same conventions as value().
+
+pub fn value_json(v: Value) -> String {
+    let s = value(v);
+    let mut iter = s.split_whitespace();
+    format!("{{\"{}\":{}}}", iter.next().unwrap(), iter.next().unwrap())
+}
+
+// win_rate_model() estimates the win rate in per mille for the side to move
+// from its score and the game ply, using a logistic model fitted to
+// self-play games.
+
+fn win_rate_model(v: Value, ply: i32) -> i32 {
+    let m = std::cmp::min(240, ply) as f64 / 64.0;
+
+    const AS: [f64; 4] = [-3.68389304, 30.07065921, -60.52878723, 149.53378557];
+    const BS: [f64; 4] = [-2.0181857, 15.85685038, -29.83452023, 47.59078827];
+    let a = ((AS[0] * m + AS[1]) * m + AS[2]) * m + AS[3];
+    let b = ((BS[0] * m + BS[1]) * m + BS[2]) * m + BS[3];
+
+    // The model was fitted with a pawn value of 208 internal units
+    let x = (v.0 * 208 / PawnValueEg.0).clamp(-2000, 2000) as f64;
+
+    (0.5 + 1000.0 / (1.0 + ((a - x) / b).exp())) as i32
+}
+
+// wdl() returns the win, draw and loss probabilities in per mille for the
+// side to move.
+
+pub fn wdl(v: Value, ply: i32) -> [i32; 3] {
+    let w = win_rate_model(v, ply);
+    let l = win_rate_model(-v, ply);
+    [w, 1000 - w - l, l]
 }
 
 // value() converts a Value to a string suitable for use with the UCI
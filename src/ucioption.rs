@@ -1,5 +1,6 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use cluster;
 use tb;
 use threads;
 use tt;
@@ -80,18 +81,56 @@ impl OptVal {
 }
 
 fn on_clear_hash(_: &OptVal) {
-    tt::clear();
+    threads::wait_for_all();
+    threads::clear_tt();
+    threads::wait_for_all();
 }
 
 fn on_hash_size(opt_val: &OptVal) {
     if let &OptVal::Spin { cur, .. } = opt_val {
-        tt::resize(cur as usize);
+        tt::resize(cur as usize, get_bool("Large Pages"));
+    }
+}
+
+fn on_large_pages(opt_val: &OptVal) {
+    if let &OptVal::Check { cur, .. } = opt_val {
+        tt::resize(get_i32("Hash") as usize, cur);
     }
 }
 
 fn on_threads(opt_val: &OptVal) {
     if let &OptVal::Spin { cur, .. } = opt_val {
-        threads::set(cur as usize);
+        if !get_bool("Deterministic") {
+            threads::set(cur as usize);
+        }
+    }
+}
+
+fn on_deterministic(opt_val: &OptVal) {
+    if let &OptVal::Check { cur, .. } = opt_val {
+        // A deterministic search is always single-threaded
+        threads::set(if cur { 1 } else { get_i32("Threads") as usize });
+    }
+}
+
+// The pawn and material hash tables have a power of 2 number of entries
+fn on_table_entries(opt_val: &OptVal) {
+    if let &OptVal::Spin { cur, .. } = opt_val {
+        let entries = threads::floor_pow2(cur);
+        if entries != cur as usize {
+            println!("info string Hash table size rounded down to {} entries",
+                entries);
+        }
+    }
+}
+
+fn on_thread_binding(_: &OptVal) {
+    threads::rebind();
+}
+
+fn on_cluster(opt_val: &OptVal) {
+    if let OptVal::StringOpt { cur, .. } = opt_val {
+        cluster::listen(cur);
     }
 }
 
@@ -110,15 +149,46 @@ pub fn init()
     opts.push(Opt::new("Analysis Contempt",
         OptVal::combo("Off var Off var White var Black"), None));
     opts.push(Opt::new("Threads", OptVal::spin(1, 1, 512), Some(on_threads)));
+    opts.push(Opt::new("Thread Binding", OptVal::string("none"),
+        Some(on_thread_binding)));
+    opts.push(Opt::new("Thread Voting", OptVal::check(true), None));
+    opts.push(Opt::new("Deterministic", OptVal::check(false),
+        Some(on_deterministic)));
     opts.push(Opt::new("Hash", OptVal::spin(16, 1, 128 * 1024),
         Some(on_hash_size)));
+    opts.push(Opt::new("Large Pages", OptVal::check(false),
+        Some(on_large_pages)));
+    opts.push(Opt::new("Pawn Hash Entries",
+        OptVal::spin(16384, 256, 1 << 22), Some(on_table_entries)));
+    opts.push(Opt::new("Material Hash Entries",
+        OptVal::spin(8192, 256, 1 << 22), Some(on_table_entries)));
     opts.push(Opt::new("Clear Hash", OptVal::Button, Some(on_clear_hash)));
     opts.push(Opt::new("Ponder", OptVal::check(false), None));
+    opts.push(Opt::new("Mate Checks Only", OptVal::check(true), None));
+    opts.push(Opt::new("PN Memory", OptVal::spin(64, 1, 4096), None));
+    opts.push(Opt::new("Cluster", OptVal::string("<empty>"),
+        Some(on_cluster)));
+    opts.push(Opt::new("Cluster TT Depth", OptVal::spin(8, 1, 100), None));
+    opts.push(Opt::new("SearchMode", OptVal::combo("AB var AB var MCTS"),
+        None));
+    opts.push(Opt::new("MCTS Memory", OptVal::spin(256, 1, 65536), None));
     opts.push(Opt::new("MultiPV", OptVal::spin(1, 1, 500), None));
     opts.push(Opt::new("Move Overhead", OptVal::spin(30, 0, 5000), None));
     opts.push(Opt::new("Minimum Thinking Time", OptVal::spin(20, 0, 5000),
         None));
     opts.push(Opt::new("Slow Mover", OptVal::spin(84, 10, 1000), None));
+    opts.push(Opt::new("nodestime", OptVal::spin(0, 0, 10000), None));
+    opts.push(Opt::new("TimeLog", OptVal::string("<empty>"), None));
+    #[cfg(feature = "treedump")]
+    {
+        opts.push(Opt::new("Tree Dump", OptVal::string("<empty>"), None));
+        opts.push(Opt::new("Tree Dump Plies", OptVal::spin(3, 0, 100),
+            None));
+        opts.push(Opt::new("Tree Dump Root Move", OptVal::string("<empty>"),
+            None));
+    }
+    opts.push(Opt::new("Output Format", OptVal::combo("UCI var UCI var JSON"),
+        None));
     opts.push(Opt::new("UCI_AnalyseMode", OptVal::check(false), None));
     opts.push(Opt::new("UCI_Chess960", OptVal::check(false), None));
     opts.push(Opt::new("SyzygyPath", OptVal::string("<empty>"),
@@ -154,12 +224,43 @@ pub fn print() {
     std::mem::forget(opts);
 }
 
-pub fn set(key: &str, val: &str) {
+// check() verifies that the option exists and that the value is valid for
+// its type. On failure the error message is returned.
+
+pub fn check(key: &str, val: &str) -> Result<(), String> {
+    let opts = unsafe { Box::from_raw(OPTIONS) };
+    let res = match opts.iter().find(|o| o.key == key) {
+        None => Err(format!("No such option: {}", key)),
+        Some(opt) => match opt.val {
+            OptVal::Spin { .. } if val.parse::<i32>().is_err() =>
+                Err(format!("Invalid value for {}: {}", key, val)),
+            OptVal::Check { .. } if val != "true" && val != "false" =>
+                Err(format!("Invalid value for {}: {}", key, val)),
+            OptVal::Combo { def, .. } if !def.split(" var ").skip(1)
+                    .any(|v| v.eq_ignore_ascii_case(val)) =>
+                Err(format!("Invalid value for {}: {}", key, val)),
+            _ => Ok(()),
+        },
+    };
+    std::mem::forget(opts);
+    res
+}
+
+// set() updates the option to the given value. It returns false, leaving
+// the option unchanged, if check() rejects the value.
+
+pub fn set(key: &str, val: &str) -> bool {
+    if let Err(msg) = check(key, val) {
+        println!("{}", msg);
+        return false;
+    }
     let mut opts = unsafe { Box::from_raw(OPTIONS) };
     if let Some(opt) = opts.iter_mut().find(|ref o| o.key == key) {
         match opt.val {
             OptVal::StringOpt { ref mut cur, .. } => *cur = String::from(val),
-            OptVal::Spin { ref mut cur, .. } => *cur = val.parse().unwrap(),
+            OptVal::Spin { ref mut cur, min, max, .. } =>
+                *cur = std::cmp::min(std::cmp::max(val.parse().unwrap(), min),
+                    max),
             OptVal::Check { ref mut cur, .. } => *cur = val == "true",
             OptVal::Button => {},
             OptVal::Combo { ref mut cur, .. } =>
@@ -168,12 +269,11 @@ pub fn set(key: &str, val: &str) {
         if let Some(on_change) = opt.on_change {
             on_change(&opt.val);
         }
-    } else {
-        println!("No such option: {}", key);
     }
     unsafe {
         OPTIONS = Box::into_raw(opts);
     }
+    true
 }
 
 pub fn get_i32(key: &str) -> i32 {
@@ -0,0 +1,30 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Runs the default bench at reduced depth and compares the total node count
+// with a recorded signature. Any functional change to search or evaluation
+// changes the signature; when that is intended, update BENCH_SIGNATURE.
+
+use std::process::Command;
+
+const BENCH_DEPTH: &str = "8";
+const BENCH_SIGNATURE: u64 = 307445;
+
+#[test]
+fn bench_signature() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rustfish"))
+        .args(["bench", "16", "1", BENCH_DEPTH])
+        .output()
+        .expect("failed to start engine");
+    assert!(output.status.success());
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let nodes: u64 = stderr.lines()
+        .find(|l| l.starts_with("Nodes searched"))
+        .and_then(|l| l.split(':').nth(1))
+        .expect("no node count in bench output")
+        .trim()
+        .parse()
+        .unwrap();
+
+    assert_eq!(nodes, BENCH_SIGNATURE);
+}
@@ -0,0 +1,56 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Starts a cluster master and one worker over a Unix socket and checks that
+// the worker connects and that the master's search completes with it.
+
+#![cfg(unix)]
+
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+fn engine(args: &[&str], stdout: Stdio) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_rustfish"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(stdout)
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to start engine")
+}
+
+fn send(stdin: &mut ChildStdin, cmd: &str) {
+    stdin.write_all(cmd.as_bytes()).unwrap();
+    stdin.write_all(b"\n").unwrap();
+    stdin.flush().unwrap();
+}
+
+#[test]
+fn master_and_worker_search() {
+    let socket = std::env::temp_dir()
+        .join(format!("rustfish-cluster-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&socket);
+    let address = format!("unix:{}", socket.display());
+
+    let mut master = engine(&[], Stdio::piped());
+    let mut stdin = master.stdin.take().unwrap();
+    let mut lines = BufReader::new(master.stdout.take().unwrap()).lines()
+        .map(|l| l.unwrap());
+    send(&mut stdin, &format!("setoption name Cluster value {}", address));
+    assert!(lines.any(|l| l.contains("Cluster listening on")));
+
+    // The worker's output is not read, so it must not fill up a pipe
+    let mut worker = engine(&["worker", &address], Stdio::null());
+    assert!(lines.any(|l| l == "info string Cluster worker 0 connected"));
+
+    send(&mut stdin, "position startpos moves e2e4");
+    send(&mut stdin, "go depth 10");
+    let bestmove = lines.find(|l| l.starts_with("bestmove"))
+        .expect("no bestmove from the master");
+    assert!(bestmove.split_whitespace().nth(1).unwrap() != "(none)");
+
+    // The worker quits when the master goes away
+    send(&mut stdin, "quit");
+    assert!(master.wait().unwrap().success());
+    assert!(worker.wait().unwrap().success());
+    let _ = std::fs::remove_file(&socket);
+}
@@ -0,0 +1,50 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Runs the bench positions twice in deterministic mode and checks that
+// every search reports the same node count, score, PV and best move.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+fn run_bench(commands: &str) -> Vec<String> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rustfish"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to start engine");
+
+    child.stdin.take().unwrap().write_all(commands.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    // Keep only the PV lines and best moves, dropping the fields that
+    // depend on the wall clock.
+    String::from_utf8(output.stdout).unwrap().lines()
+        .filter(|l| l.starts_with("bestmove") || l.contains(" pv "))
+        .map(|l| {
+            let mut tokens = l.split_whitespace();
+            let mut kept = Vec::new();
+            while let Some(t) = tokens.next() {
+                match t {
+                    "nps" | "time" | "hashfull" => { tokens.next(); }
+                    _ => kept.push(t),
+                }
+            }
+            kept.join(" ")
+        })
+        .collect()
+}
+
+#[test]
+fn go_nodes_is_reproducible() {
+    let commands = "setoption name Deterministic value true\n\
+                    bench 16 4 10000 default nodes\n\
+                    quit\n";
+
+    let first = run_bench(commands);
+    let second = run_bench(commands);
+
+    assert!(first.iter().filter(|l| l.starts_with("bestmove")).count() > 40);
+    assert_eq!(first, second);
+}
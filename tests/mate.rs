@@ -0,0 +1,49 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Runs "go mate" on positions with a known shortest mate and checks the
+// mating move, the score and the report when there is no mate.
+
+use std::process::Command;
+
+fn go_mate(fen: &str, moves: u32, checks_only: bool) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_rustfish"))
+        .args(["--option", &format!("Mate Checks Only={}", checks_only),
+            "analyse", "--fen", fen, "--mate", &moves.to_string()])
+        .output()
+        .expect("failed to start engine");
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn bestmove(output: &str) -> &str {
+    output.lines().find(|l| l.starts_with("bestmove"))
+        .and_then(|l| l.split_whitespace().nth(1))
+        .expect("no bestmove")
+}
+
+#[test]
+fn mate_in_one() {
+    let output = go_mate("6k1/5ppp/8/8/8/8/8/R5K1 w - -", 1, true);
+    assert_eq!(bestmove(&output), "a1a8");
+    assert!(output.contains("score mate 1"));
+}
+
+#[test]
+fn mate_in_two_with_quiet_move() {
+    // 1. Kf7 Kh7 2. Rh1#, found only when quiet moves are tried
+    let fen = "7k/8/5K2/8/8/8/8/6R1 w - -";
+    let output = go_mate(fen, 2, false);
+    assert_eq!(bestmove(&output), "f6f7");
+    assert!(output.contains("score mate 2"));
+
+    let output = go_mate(fen, 2, true);
+    assert_eq!(bestmove(&output), "0000");
+    assert!(output.contains("info string No mate in 2 found"));
+}
+
+#[test]
+fn no_mate() {
+    let output = go_mate("startpos", 2, false);
+    assert_eq!(bestmove(&output), "0000");
+    assert!(output.contains("info string No mate in 2 found"));
+}
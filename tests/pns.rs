@@ -0,0 +1,54 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Runs the "pns" command on small endgames with a known outcome and checks
+// that the node and time limits end an unsolvable search.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// pns() returns the result and the nodes visited of "pns <args>" in the
+// given position
+fn pns(fen: &str, args: &str) -> (String, u64) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rustfish"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to start engine");
+
+    let commands = format!("position {}\npns {}\nquit\n", fen, args);
+    child.stdin.take().unwrap().write_all(commands.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let line = stdout.lines()
+        .find(|l| l.starts_with("info string pns result"))
+        .expect("no pns result");
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    (tokens[4].to_string(), tokens[6].parse().unwrap())
+}
+
+#[test]
+fn krk_is_a_win() {
+    let (result, _) = pns("fen k7/8/2K5/8/8/8/8/7R w - -", "");
+    assert_eq!(result, "win");
+}
+
+#[test]
+fn knk_is_a_draw() {
+    let (result, _) = pns("fen 8/8/8/4k3/8/8/8/4K1N1 w - -", "");
+    assert_eq!(result, "draw");
+    let (result, _) = pns("fen 8/8/8/4k3/8/8/8/4K1N1 b - -", "pn2");
+    assert_eq!(result, "draw");
+}
+
+#[test]
+fn limits_stop_the_search() {
+    let (result, nodes) = pns("startpos", "nodes 5000");
+    assert_eq!(result, "unknown");
+    assert!((5000..5100).contains(&nodes));
+
+    let (result, _) = pns("startpos", "movetime 200");
+    assert_eq!(result, "unknown");
+}